@@ -0,0 +1,201 @@
+//! Throughput of sjq on a large generated input, for each way of parsing it.
+//!
+//! Run with `cargo bench`. The size of the input in MiB can be set with `SJQ_BENCH_MB`,
+//! and another build of sjq can be measured by giving its path with `SJQ_BENCH_BIN`.
+//! Run by `cargo test`, each case is only checked once on a small input.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const DEFAULT_SIZE_MB: u64 = 64;
+const RUNS: usize = 3;
+
+/// How the input is given to sjq.
+#[derive(Clone, Copy)]
+enum Input {
+    /// Read from a pipe, through the buffered stream.
+    Pipe,
+    /// Redirected from the file, which is memory-mapped.
+    File,
+}
+
+/// How the values of the input are laid out.
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// A stream of small documents.
+    Stream,
+    /// A single document `{"a": [...], "b": {...}}`, the documents of the stream
+    /// being in the array.
+    Document,
+}
+
+struct Case {
+    name: &'static str,
+    args: &'static [&'static str],
+    input: Input,
+    layout: Layout,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "skip everything",
+        args: &[".missing"],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "skip everything, mapped",
+        args: &[".missing"],
+        input: Input::File,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "skip everything structurally",
+        args: &["--skip-strictness", "structural", ".missing"],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "skip everything structurally, mapped",
+        args: &["--skip-strictness", "structural", ".missing"],
+        input: Input::File,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "keep a part of each document",
+        args: &[".meta"],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "keep a part of each document structurally",
+        args: &["--skip-strictness", "structural", ".meta"],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "keep each document",
+        args: &["."],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "keep each document through a stage",
+        args: &[". | select .name"],
+        input: Input::Pipe,
+        layout: Layout::Stream,
+    },
+    Case {
+        name: "skip a large array",
+        args: &[".b"],
+        input: Input::Pipe,
+        layout: Layout::Document,
+    },
+    Case {
+        name: "skip a large array structurally",
+        args: &["--skip-strictness", "structural", ".b"],
+        input: Input::Pipe,
+        layout: Layout::Document,
+    },
+];
+
+/// Write documents holding every kind of value, until the file is about `size` bytes long.
+fn generate_input(path: &Path, size: u64, layout: Layout) -> std::io::Result<()> {
+    let mut output = BufWriter::new(File::create(path)?);
+    let mut written = 0;
+
+    if layout == Layout::Document {
+        output.write_all(b"{\"a\": [\n")?;
+    }
+    for i in 0.. {
+        let document = format!(
+            "{}{{\"id\": {}, \"name\": \"user {} \u{e9}t\u{e9} \\\"\\u00e9\\\"\", \"score\": {}.25e2, \
+             \"active\": {}, \"tags\": [\"x\", \"y\", null], \"meta\": {{\"k\": [1, 2, {{\"z\": -3}}]}}}}\n",
+            if i > 0 && layout == Layout::Document { "," } else { "" },
+            i,
+            i,
+            i % 1000,
+            i % 2 == 0,
+        );
+        output.write_all(document.as_bytes())?;
+        written += document.len() as u64;
+
+        if written >= size {
+            break;
+        }
+    }
+    if layout == Layout::Document {
+        output.write_all(b"],\n\"b\": {\"k\": [1, 2, 3]}}\n")?;
+    }
+
+    output.flush()
+}
+
+fn run(case: &Case, binary: &Path, input_path: &Path) -> Duration {
+    let mut command = Command::new(binary);
+    command.args(case.args).stdout(Stdio::null());
+
+    let start = Instant::now();
+    let status = match case.input {
+        Input::File => command.stdin(File::open(input_path).unwrap()).status(),
+        Input::Pipe => {
+            let mut child = command.stdin(Stdio::piped()).spawn().unwrap();
+            let mut stdin = child.stdin.take().unwrap();
+            std::io::copy(&mut File::open(input_path).unwrap(), &mut stdin).unwrap();
+            drop(stdin);
+            child.wait()
+        }
+    }
+    .unwrap();
+    let elapsed = start.elapsed();
+
+    assert!(status.success(), "sjq failed on the case {}", case.name);
+
+    elapsed
+}
+
+fn main() {
+    // `cargo bench` gives this flag, unlike `cargo test`.
+    let benchmarking = std::env::args().any(|arg| arg == "--bench");
+    let size_mb = std::env::var("SJQ_BENCH_MB")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(if benchmarking { DEFAULT_SIZE_MB } else { 1 });
+    let runs = if benchmarking { RUNS } else { 1 };
+    let binary = std::env::var_os("SJQ_BENCH_BIN")
+        .map_or_else(|| PathBuf::from(env!("CARGO_BIN_EXE_sjq")), PathBuf::from);
+    let stream_path = std::env::temp_dir().join("sjq_bench_stream.json");
+    let document_path = std::env::temp_dir().join("sjq_bench_document.json");
+
+    generate_input(&stream_path, size_mb * 1024 * 1024, Layout::Stream).unwrap();
+    generate_input(&document_path, size_mb * 1024 * 1024, Layout::Document).unwrap();
+    println!("inputs of {} MiB", size_mb);
+
+    for case in CASES {
+        let input_path = match case.layout {
+            Layout::Stream => &stream_path,
+            Layout::Document => &document_path,
+        };
+        let input_len = std::fs::metadata(input_path).unwrap().len();
+
+        // The best of a few runs, the others being slowed down by the rest of the system.
+        let best = (0..runs)
+            .map(|_| run(case, &binary, input_path))
+            .min()
+            .unwrap();
+        let throughput = input_len as f64 / (1024. * 1024.) / best.as_secs_f64();
+
+        println!(
+            "{:<44} {:>8.2} s {:>8.1} MiB/s",
+            case.name,
+            best.as_secs_f64(),
+            throughput
+        );
+    }
+
+    std::fs::remove_file(&stream_path).unwrap();
+    std::fs::remove_file(&document_path).unwrap();
+}
@@ -1,7 +1,11 @@
 use lexical;
+use std::str::FromStr;
 use structopt::StructOpt;
 
+use crate::output::Compression;
+use crate::parse_and_skip::Strictness;
 use crate::parse_basics::NUMBER_MAX_LENGTH;
+use crate::recovery::ErrorPolicy;
 
 fn validate_max_text_length(val: String) -> Result<(), String> {
     let val: usize = lexical::parse(val).map_err(|_| "max_text_length is too big".to_string())?;
@@ -16,6 +20,92 @@ fn validate_max_text_length(val: String) -> Result<(), String> {
     }
 }
 
+/// Parses a size in bytes, with an optional K, M, G or T binary unit suffix.
+fn parse_size(val: &str) -> Result<u64, String> {
+    let (digits, unit) = match val.chars().last() {
+        Some('K') | Some('k') => (&val[..val.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&val[..val.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&val[..val.len() - 1], 1u64 << 30),
+        Some('T') | Some('t') => (&val[..val.len() - 1], 1u64 << 40),
+        _ => (val, 1u64),
+    };
+
+    let size: u64 = lexical::parse(digits).map_err(|_| format!("invalid size : {}", val))?;
+
+    size.checked_mul(unit)
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("invalid size : {}", val))
+}
+
+/// Parses a number of things, which can't be 0.
+fn parse_count<N: FromStr + PartialOrd + Default>(val: &str) -> Result<N, String> {
+    val.parse()
+        .ok()
+        .filter(|count| *count > N::default())
+        .ok_or_else(|| format!("invalid count : {}", val))
+}
+
+/// Encoding of the input stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputFormat, String> {
+        match s {
+            "json" => Ok(InputFormat::Json),
+            "msgpack" => Ok(InputFormat::MsgPack),
+            "cbor" => Ok(InputFormat::Cbor),
+            _ => Err(format!("unknown input format : {}", s)),
+        }
+    }
+}
+
+/// Encoding of the output stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(format!("unknown output format : {}", s)),
+        }
+    }
+}
+
+/// When to syntax-highlight the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    Auto,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ColorChoice, String> {
+        match s {
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            "auto" => Ok(ColorChoice::Auto),
+            _ => Err(format!("unknown color choice : {}", s)),
+        }
+    }
+}
+
 /// Filter, map and aggregate huge or streaming json content
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -38,10 +128,95 @@ pub struct ArgStruct {
     #[structopt(short, long, requires = "output")]
     pub force_new: bool,
 
+    /// Encoding of the output, with yaml each output item is written as its own document
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        default_value = "json",
+        possible_values = &["json", "yaml"]
+    )]
+    pub output_format: OutputFormat,
+
+    /// Splits the output into numbered files of about this size (e.g. 1G, 500M, 64K)
+    #[structopt(
+        long,
+        value_name = "SIZE",
+        parse(try_from_str = parse_size),
+        requires = "output",
+        conflicts_with = "partition-by"
+    )]
+    pub split_size: Option<u64>,
+
+    /// Splits the output into numbered files of at most this number of items
+    #[structopt(
+        long,
+        value_name = "N",
+        parse(try_from_str = parse_count),
+        requires = "output",
+        conflicts_with = "partition-by"
+    )]
+    pub split_lines: Option<u64>,
+
+    /// Writes each output item into the file of its partition, named by replacing {} in the
+    /// output filename by the value found at this path
+    #[structopt(long, value_name = "PATH", requires = "output")]
+    pub partition_by: Option<String>,
+
+    /// Max number of partition files open at the same time, 64 by default
+    #[structopt(
+        long,
+        value_name = "N",
+        parse(try_from_str = parse_count),
+        requires = "partition-by"
+    )]
+    pub max_open_files: Option<usize>,
+
+    /// Compresses the output, inferred from a .gz or .zst output filename if not specified
+    #[structopt(
+        long,
+        value_name = "ALGORITHM",
+        possible_values = &["gzip", "zstd"]
+    )]
+    pub compress: Option<Compression>,
+
+    /// Writes the output file only once the whole input is successfully processed,
+    /// leaving any previous file untouched on failure
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &["append", "partition-by", "split-size", "split-lines"]
+    )]
+    pub atomic: bool,
+
     /// Prettify json output
     #[structopt(short, long)]
     pub pretty: bool,
 
+    /// Prettify json output, indenting with the given number of spaces, or compact if 0
+    #[structopt(long, value_name = "N", conflicts_with = "tab")]
+    pub indent: Option<usize>,
+
+    /// Prettify json output, indenting with tabs
+    #[structopt(long)]
+    pub tab: bool,
+
+    /// Sort the fields of the json objects by key
+    #[structopt(long)]
+    pub sort_keys: bool,
+
+    /// Escape the non-ASCII characters of the json strings
+    #[structopt(long)]
+    pub ascii_output: bool,
+
+    /// Colorize json output, auto enables it when writing to a terminal and NO_COLOR is not set
+    #[structopt(
+        long,
+        value_name = "WHEN",
+        default_value = "auto",
+        possible_values = &["always", "never", "auto"]
+    )]
+    pub color: ColorChoice,
+
     /// Max length of a string value, a field name or a regex
     #[structopt(
         short,
@@ -51,6 +226,99 @@ pub struct ArgStruct {
     )]
     pub max_text_length: usize,
 
+    /// Validation of the parts of the input which are not kept, structural only checks that
+    /// their strings are closed and their brackets balanced but skips them much faster
+    #[structopt(
+        long,
+        value_name = "LEVEL",
+        default_value = "full",
+        possible_values = &["full", "structural"]
+    )]
+    pub skip_strictness: Strictness,
+
+    /// Keeps the numbers not fitting in 64 bits, such as big integers and long decimals, as
+    /// their original digits, so they are written back exactly
+    #[structopt(long)]
+    pub arbitrary_precision: bool,
+
+    /// Rejects the input not conforming to RFC 8259, like numbers with leading zeros or a sign
+    /// `+`, invalid escape sequences, unescaped control characters or invalid UTF-8
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Only checks that the input is a stream of JSON values conforming to RFC 8259, reporting
+    /// its first violation
+    #[structopt(long, conflicts_with_all = &["query", "queries", "ndjson"])]
+    pub validate: bool,
+
+    /// Reads the input as newline-delimited JSON, each line holding whole values,
+    /// so the lines can be processed in parallel
+    #[structopt(long)]
+    pub ndjson: bool,
+
+    /// Number of threads processing the lines of a --ndjson input, the number of CPUs by default
+    #[structopt(long, value_name = "N", requires = "ndjson")]
+    pub threads: Option<usize>,
+
+    /// What to do with a malformed line of a --ndjson input : stop, skip it, or skip it with
+    /// a warning, fail by default
+    #[structopt(
+        long,
+        value_name = "POLICY",
+        possible_values = &["fail", "skip", "warn"],
+        requires = "ndjson"
+    )]
+    pub on_error: Option<ErrorPolicy>,
+
+    /// Writes each skipped line into this file, as a json line with its offset, line, column,
+    /// error message and raw text
+    #[structopt(long, value_name = "FILE", requires = "on-error")]
+    pub errors_to: Option<String>,
+
+    /// Writes the items failing a stage of the queries into this file instead of stopping,
+    /// as json lines with the stage, the error, the path in the input of the failing value
+    /// and the item
+    #[structopt(long, value_name = "FILE")]
+    pub dead_letter: Option<String>,
+
+    /// Encoding of the input stream, binary items are processed like their JSON equivalent
+    #[structopt(
+        short,
+        long,
+        value_name = "FORMAT",
+        default_value = "json",
+        possible_values = &["json", "msgpack", "cbor"]
+    )]
+    pub input_format: InputFormat,
+
+    /// Wraps each kept value with its path, as {"path": ..., "value": ...}
+    #[structopt(long, conflicts_with = "paths-only")]
+    pub with_path: bool,
+
+    /// Outputs the path of each kept value instead of the value
+    #[structopt(long)]
+    pub paths_only: bool,
+
+    /// Outputs the kept values as [path, leaf] events, like jq tostream, without loading them,
+    /// each kept value being otherwise held whole in memory even when written as it is
+    #[structopt(long, conflicts_with_all = &["with-path", "paths-only"])]
+    pub stream: bool,
+
+    /// Additional query run over the same parse of the input, can be repeated
+    #[structopt(
+        short = "e",
+        long = "query",
+        value_name = "QUERY",
+        number_of_values = 1
+    )]
+    pub queries: Vec<String>,
+
+    /// Writes the output of the additional query of the same rank into a file instead of
+    /// the main output, can be repeated
+    #[structopt(long, value_name = "FILE", number_of_values = 1, requires = "queries")]
+    pub query_output: Vec<String>,
+
     /// Filter and pipeline query
-    pub query: String,
+    #[structopt(required_unless_one = &["queries", "validate"])]
+    pub query: Option<String>,
 }
@@ -0,0 +1,588 @@
+use std::io::{self, Read, Write};
+
+/// A structural event of a binary encoded document.
+///
+/// The binary formats share the JSON data model (plus a few extras like raw bytes),
+/// so their items can be described with the same events.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Unsigned(u64),
+    /// Integer below `i64::MIN`, equal to `-1 - n`, which only CBOR can hold.
+    NegativeUnsigned(u64),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    /// Start of an array, with its number of elements if known in advance.
+    ArrayStart(Option<u64>),
+    /// Start of a map, with its number of entries if known in advance.
+    MapStart(Option<u64>),
+    /// End of a container whose length wasn't known in advance.
+    Break,
+}
+
+pub trait EventDecoder {
+    /// Decode the next event of the input.
+    /// Returns `None` if the input is cleanly done.
+    fn next_event(&mut self) -> io::Result<Option<Event>>;
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read a single byte, returns `None` at the end of the input.
+fn read_first_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    input.read_exact(&mut buf)?;
+
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    input.read_exact(&mut buf)?;
+
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_bytes<R: Read>(input: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let read_len = input.by_ref().take(len).read_to_end(&mut buf)?;
+
+    if (read_len as u64) < len {
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    } else {
+        Ok(buf)
+    }
+}
+
+fn read_string<R: Read>(input: &mut R, len: u64) -> io::Result<String> {
+    String::from_utf8(read_bytes(input, len)?).map_err(|_| invalid_data("invalid UTF-8 string"))
+}
+
+/// Decoder of a stream of MessagePack items.
+pub struct MsgPackDecoder<R: Read>(R);
+
+impl<R: Read> MsgPackDecoder<R> {
+    pub fn new(input: R) -> MsgPackDecoder<R> {
+        MsgPackDecoder(input)
+    }
+}
+
+/// Read the content of a MessagePack extension, the type of the extension is dropped.
+fn read_ext<R: Read>(input: &mut R, len: u64) -> io::Result<Event> {
+    read_u8(input)?;
+
+    Ok(Event::Bytes(read_bytes(input, len)?))
+}
+
+impl<R: Read> EventDecoder for MsgPackDecoder<R> {
+    fn next_event(&mut self) -> io::Result<Option<Event>> {
+        let marker = match read_first_byte(&mut self.0)? {
+            Some(marker) => marker,
+            None => return Ok(None),
+        };
+
+        let input = &mut self.0;
+
+        let event = match marker {
+            0x00..=0x7f => Event::Unsigned(u64::from(marker)),
+            0x80..=0x8f => Event::MapStart(Some(u64::from(marker & 0x0f))),
+            0x90..=0x9f => Event::ArrayStart(Some(u64::from(marker & 0x0f))),
+            0xa0..=0xbf => Event::String(read_string(input, u64::from(marker & 0x1f))?),
+            0xc0 => Event::Null,
+            0xc1 => return Err(invalid_data("invalid MessagePack marker 0xc1")),
+            0xc2 => Event::Boolean(false),
+            0xc3 => Event::Boolean(true),
+            0xc4 => {
+                let len = read_u8(input)?;
+                Event::Bytes(read_bytes(input, u64::from(len))?)
+            }
+            0xc5 => {
+                let len = read_u16(input)?;
+                Event::Bytes(read_bytes(input, u64::from(len))?)
+            }
+            0xc6 => {
+                let len = read_u32(input)?;
+                Event::Bytes(read_bytes(input, u64::from(len))?)
+            }
+            0xc7 => {
+                let len = read_u8(input)?;
+                read_ext(input, u64::from(len))?
+            }
+            0xc8 => {
+                let len = read_u16(input)?;
+                read_ext(input, u64::from(len))?
+            }
+            0xc9 => {
+                let len = read_u32(input)?;
+                read_ext(input, u64::from(len))?
+            }
+            0xca => Event::Float(f64::from(f32::from_bits(read_u32(input)?))),
+            0xcb => Event::Float(f64::from_bits(read_u64(input)?)),
+            0xcc => Event::Unsigned(u64::from(read_u8(input)?)),
+            0xcd => Event::Unsigned(u64::from(read_u16(input)?)),
+            0xce => Event::Unsigned(u64::from(read_u32(input)?)),
+            0xcf => Event::Unsigned(read_u64(input)?),
+            0xd0 => Event::Integer(i64::from(read_u8(input)? as i8)),
+            0xd1 => Event::Integer(i64::from(read_u16(input)? as i16)),
+            0xd2 => Event::Integer(i64::from(read_u32(input)? as i32)),
+            0xd3 => Event::Integer(read_u64(input)? as i64),
+            0xd4 => read_ext(input, 1)?,
+            0xd5 => read_ext(input, 2)?,
+            0xd6 => read_ext(input, 4)?,
+            0xd7 => read_ext(input, 8)?,
+            0xd8 => read_ext(input, 16)?,
+            0xd9 => {
+                let len = read_u8(input)?;
+                Event::String(read_string(input, u64::from(len))?)
+            }
+            0xda => {
+                let len = read_u16(input)?;
+                Event::String(read_string(input, u64::from(len))?)
+            }
+            0xdb => {
+                let len = read_u32(input)?;
+                Event::String(read_string(input, u64::from(len))?)
+            }
+            0xdc => Event::ArrayStart(Some(u64::from(read_u16(input)?))),
+            0xdd => Event::ArrayStart(Some(u64::from(read_u32(input)?))),
+            0xde => Event::MapStart(Some(u64::from(read_u16(input)?))),
+            0xdf => Event::MapStart(Some(u64::from(read_u32(input)?))),
+            0xe0..=0xff => Event::Integer(i64::from(marker as i8)),
+        };
+
+        Ok(Some(event))
+    }
+}
+
+const CBOR_BREAK: u8 = 0xff;
+
+/// Decoder of a stream of CBOR items (RFC 7049).
+pub struct CborDecoder<R: Read>(R);
+
+impl<R: Read> CborDecoder<R> {
+    pub fn new(input: R) -> CborDecoder<R> {
+        CborDecoder(input)
+    }
+
+    /// Read the argument following an initial byte.
+    /// Returns `None` if the item is of indefinite length.
+    fn read_argument(&mut self, additional_info: u8) -> io::Result<Option<u64>> {
+        match additional_info {
+            0..=23 => Ok(Some(u64::from(additional_info))),
+            24 => Ok(Some(u64::from(read_u8(&mut self.0)?))),
+            25 => Ok(Some(u64::from(read_u16(&mut self.0)?))),
+            26 => Ok(Some(u64::from(read_u32(&mut self.0)?))),
+            27 => Ok(Some(read_u64(&mut self.0)?)),
+            31 => Ok(None),
+            _ => Err(invalid_data("invalid CBOR additional information")),
+        }
+    }
+
+    /// Read a byte or text string, concatenating the chunks of indefinite length strings.
+    fn read_chunks(&mut self, major_type: u8, len: Option<u64>) -> io::Result<Vec<u8>> {
+        if let Some(len) = len {
+            return read_bytes(&mut self.0, len);
+        }
+
+        let mut acc = Vec::new();
+
+        loop {
+            let initial_byte = read_u8(&mut self.0)?;
+
+            if initial_byte == CBOR_BREAK {
+                return Ok(acc);
+            }
+
+            if initial_byte >> 5 != major_type {
+                return Err(invalid_data("wrong type of chunk in CBOR string"));
+            }
+
+            match self.read_argument(initial_byte & 0x1f)? {
+                Some(chunk_len) => acc.extend(read_bytes(&mut self.0, chunk_len)?),
+                None => return Err(invalid_data("nested indefinite length CBOR string")),
+            }
+        }
+    }
+}
+
+/// Convert an IEEE 754 half-precision float to a double-precision one.
+fn f16_to_f64(half: u16) -> f64 {
+    let exponent = i32::from((half >> 10) & 0x1f);
+    let mantissa = f64::from(half & 0x3ff);
+
+    let value = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent - 25),
+    };
+
+    if half & 0x8000 == 0 {
+        value
+    } else {
+        -value
+    }
+}
+
+impl<R: Read> EventDecoder for CborDecoder<R> {
+    fn next_event(&mut self) -> io::Result<Option<Event>> {
+        let mut initial_byte = match read_first_byte(&mut self.0)? {
+            Some(initial_byte) => initial_byte,
+            None => return Ok(None),
+        };
+
+        // Tags only add semantic to the next item, they are skipped.
+        while initial_byte >> 5 == 6 {
+            self.read_argument(initial_byte & 0x1f)?;
+            initial_byte = read_u8(&mut self.0)?;
+        }
+
+        let major_type = initial_byte >> 5;
+        let additional_info = initial_byte & 0x1f;
+
+        if major_type == 7 {
+            let event = match additional_info {
+                20 => Event::Boolean(false),
+                21 => Event::Boolean(true),
+                22 | 23 => Event::Null,
+                25 => Event::Float(f16_to_f64(read_u16(&mut self.0)?)),
+                26 => Event::Float(f64::from(f32::from_bits(read_u32(&mut self.0)?))),
+                27 => Event::Float(f64::from_bits(read_u64(&mut self.0)?)),
+                31 => Event::Break,
+                _ => return Err(invalid_data("unsupported CBOR simple value")),
+            };
+
+            return Ok(Some(event));
+        }
+
+        let argument = self.read_argument(additional_info)?;
+
+        let event = match (major_type, argument) {
+            (0, Some(n)) => Event::Unsigned(n),
+            (1, Some(n)) if n <= i64::MAX as u64 => Event::Integer(-1 - n as i64),
+            (1, Some(n)) => Event::NegativeUnsigned(n),
+            (2, len) => Event::Bytes(self.read_chunks(major_type, len)?),
+            (3, len) => Event::String(
+                String::from_utf8(self.read_chunks(major_type, len)?)
+                    .map_err(|_| invalid_data("invalid UTF-8 string"))?,
+            ),
+            (4, len) => Event::ArrayStart(len),
+            (5, len) => Event::MapStart(len),
+            _ => return Err(invalid_data("invalid CBOR item")),
+        };
+
+        Ok(Some(event))
+    }
+}
+
+struct Frame {
+    is_map: bool,
+    /// Number of items (keys and values for a map) expected, if known.
+    expected: Option<u64>,
+    written: u64,
+}
+
+/// Adapter converting a stream of binary encoded items into a stream of JSON documents,
+/// one per line, so they can be parsed like any JSON input.
+///
+/// Raw bytes are written as arrays of numbers, non-string map keys are stringified
+/// and non-finite floats are written as `null`.
+pub struct JsonTranscoder<D: EventDecoder> {
+    decoder: D,
+    stack: Vec<Frame>,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<D: EventDecoder> JsonTranscoder<D> {
+    pub fn new(decoder: D) -> JsonTranscoder<D> {
+        JsonTranscoder {
+            decoder,
+            stack: Vec::new(),
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Write the separator preceding a new item.
+    /// Returns true if this item is a key of a map.
+    fn begin_item(&mut self) -> bool {
+        if let Some(frame) = self.stack.last() {
+            let is_value = frame.is_map && frame.written % 2 == 1;
+
+            if is_value {
+                self.buffer.push(b':');
+            } else if frame.written > 0 {
+                self.buffer.push(b',');
+            }
+
+            frame.is_map && !is_value
+        } else {
+            false
+        }
+    }
+
+    /// Count a completed item in its container, and close the containers which are now full.
+    fn end_item(&mut self) {
+        loop {
+            match self.stack.last_mut() {
+                None => {
+                    self.buffer.push(b'\n');
+                    return;
+                }
+                Some(frame) => {
+                    frame.written += 1;
+
+                    if frame.expected != Some(frame.written) {
+                        return;
+                    }
+                }
+            }
+
+            self.close_frame();
+        }
+    }
+
+    fn open_frame(&mut self, is_map: bool, len: Option<u64>) -> io::Result<()> {
+        // A map holds a key and a value per entry.
+        let expected = match len {
+            Some(len) if is_map => Some(
+                len.checked_mul(2)
+                    .ok_or_else(|| invalid_data("too many entries in map"))?,
+            ),
+            len => len,
+        };
+
+        self.buffer.push(if is_map { b'{' } else { b'[' });
+        self.stack.push(Frame {
+            is_map,
+            expected,
+            written: 0,
+        });
+
+        if expected == Some(0) {
+            self.close_frame();
+            self.end_item();
+        }
+
+        Ok(())
+    }
+
+    fn close_frame(&mut self) {
+        if let Some(frame) = self.stack.pop() {
+            self.buffer.push(if frame.is_map { b'}' } else { b']' });
+        }
+    }
+
+    fn write_scalar(&mut self, event: Event, is_key: bool) -> io::Result<()> {
+        let buffer = &mut self.buffer;
+
+        if is_key {
+            let key = match event {
+                Event::String(s) => s,
+                Event::Null => "null".to_string(),
+                Event::Boolean(b) => b.to_string(),
+                Event::Integer(i) => i.to_string(),
+                Event::Unsigned(u) => u.to_string(),
+                Event::NegativeUnsigned(n) => (-1 - i128::from(n)).to_string(),
+                Event::Float(f) => f.to_string(),
+                _ => return Err(invalid_data("unsupported map key")),
+            };
+
+            return serde_json::to_writer(buffer, &key).map_err(io::Error::from);
+        }
+
+        match event {
+            Event::Null => buffer.extend_from_slice(b"null"),
+            Event::Boolean(b) => write!(buffer, "{}", b)?,
+            Event::Integer(i) => write!(buffer, "{}", i)?,
+            Event::Unsigned(u) => write!(buffer, "{}", u)?,
+            // Written with all its digits, kept exact by the parser in arbitrary precision.
+            Event::NegativeUnsigned(n) => write!(buffer, "{}", -1 - i128::from(n))?,
+            Event::Float(f) if f.is_finite() => serde_json::to_writer(buffer, &f)?,
+            Event::Float(_) => buffer.extend_from_slice(b"null"),
+            Event::String(s) => serde_json::to_writer(buffer, &s)?,
+            Event::Bytes(bytes) => serde_json::to_writer(buffer, &bytes)?,
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Transcode the next event into the buffer.
+    /// Returns false if the input is done.
+    fn transcode_next(&mut self) -> io::Result<bool> {
+        let event = match self.decoder.next_event()? {
+            Some(event) => event,
+            None if self.stack.is_empty() => return Ok(false),
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        };
+
+        if event == Event::Break {
+            match self.stack.last() {
+                Some(frame)
+                    if frame.expected.is_none() && !(frame.is_map && frame.written % 2 == 1) =>
+                {
+                    self.close_frame();
+                    self.end_item();
+
+                    return Ok(true);
+                }
+                _ => return Err(invalid_data("unexpected break")),
+            }
+        }
+
+        let is_key = self.begin_item();
+
+        match event {
+            Event::ArrayStart(_) | Event::MapStart(_) if is_key => {
+                return Err(invalid_data("unsupported map key"))
+            }
+            Event::ArrayStart(len) => self.open_frame(false, len)?,
+            Event::MapStart(len) => self.open_frame(true, len)?,
+            scalar => {
+                self.write_scalar(scalar, is_key)?;
+                self.end_item();
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl<D: EventDecoder> Read for JsonTranscoder<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            self.buffer.clear();
+            self.pos = 0;
+
+            if !self.transcode_next()? {
+                return Ok(0);
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcode<D: EventDecoder>(decoder: D) -> String {
+        let mut output = String::new();
+        JsonTranscoder::new(decoder)
+            .read_to_string(&mut output)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn transcode_msgpack_stream() {
+        // {"a": [1, -1, true], "b": null} {"c": "word"} 3.5
+        let input: &[u8] = &[
+            0x82, 0xa1, b'a', 0x93, 0x01, 0xff, 0xc3, 0xa1, b'b', 0xc0, 0x81, 0xa1, b'c', 0xa4,
+            b'w', b'o', b'r', b'd', 0xcb, 0x40, 0x0c, 0, 0, 0, 0, 0, 0,
+        ];
+
+        assert_eq!(
+            transcode(MsgPackDecoder::new(input)),
+            "{\"a\":[1,-1,true],\"b\":null}\n{\"c\":\"word\"}\n3.5\n"
+        );
+    }
+
+    #[test]
+    fn transcode_cbor_stream() {
+        // {"a": [1, -1, [_ ]]} [_ 1, 2] 1.5 (half-precision)
+        let input: &[u8] = &[
+            0xa1, 0x61, b'a', 0x83, 0x01, 0x20, 0x9f, 0xff, 0x9f, 0x01, 0x02, 0xff, 0xf9, 0x3e,
+            0x00,
+        ];
+
+        assert_eq!(
+            transcode(CborDecoder::new(input)),
+            "{\"a\":[1,-1,[]]}\n[1,2]\n1.5\n"
+        );
+    }
+
+    #[test]
+    fn transcode_cbor_integers_below_i64_min() {
+        // -2^64 -9223372036854775809, and a map with -2^64 as key
+        let input: &[u8] = &[
+            0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3b, 0x80, 0, 0, 0, 0, 0, 0, 0,
+            0xa1, 0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xf6,
+        ];
+
+        assert_eq!(
+            transcode(CborDecoder::new(input)),
+            "-18446744073709551616\n-9223372036854775809\n{\"-18446744073709551616\":null}\n"
+        );
+    }
+
+    #[test]
+    fn transcode_empty_containers() {
+        let input: &[u8] = &[0x80, 0x90, 0x91, 0x80];
+
+        assert_eq!(transcode(MsgPackDecoder::new(input)), "{}\n[]\n[{}]\n");
+    }
+
+    #[test]
+    fn fail_on_truncated_input() {
+        let input: &[u8] = &[0x92, 0x01];
+        let mut output = String::new();
+
+        assert!(JsonTranscoder::new(MsgPackDecoder::new(input))
+            .read_to_string(&mut output)
+            .is_err());
+    }
+
+    #[test]
+    fn fail_on_too_long_map() {
+        // Map of 2^64 - 1 entries
+        let input: &[u8] = &[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let mut output = String::new();
+
+        let err = JsonTranscoder::new(CborDecoder::new(input))
+            .read_to_string(&mut output)
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
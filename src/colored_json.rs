@@ -0,0 +1,173 @@
+use std::io::{self, Write};
+
+use crate::json_format::{write_json_string, FormatOptions};
+use crate::json_value::JsonValue;
+
+// ANSI escape codes of the syntax highlighting.
+const KEY_COLOR: &str = "\x1b[1;34m";
+const STRING_COLOR: &str = "\x1b[32m";
+const NUMBER_COLOR: &str = "\x1b[36m";
+const BOOLEAN_COLOR: &str = "\x1b[33m";
+const NULL_COLOR: &str = "\x1b[1;30m";
+const RESET: &str = "\x1b[0m";
+
+struct ColoredWriter<'a> {
+    indent: Option<&'a [u8]>,
+    options: FormatOptions,
+}
+
+/// Write a value as syntax-highlighted JSON, in the same layout as `json_format::write_json`.
+pub fn write_colored<W: Write>(
+    output: &mut W,
+    value: &JsonValue,
+    indent: Option<&[u8]>,
+    options: FormatOptions,
+) -> io::Result<()> {
+    ColoredWriter { indent, options }.write_value(output, value, 0)
+}
+
+impl<'a> ColoredWriter<'a> {
+    fn write_newline_indent<W: Write>(&self, output: &mut W, depth: usize) -> io::Result<()> {
+        if let Some(indent) = self.indent {
+            writeln!(output)?;
+
+            for _ in 0..depth {
+                output.write_all(indent)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_string<W: Write>(&self, output: &mut W, s: &str, color: &str) -> io::Result<()> {
+        write!(output, "{}", color)?;
+        write_json_string(output, s, self.options)?;
+        write!(output, "{}", RESET)
+    }
+
+    fn write_value<W: Write>(
+        &self,
+        output: &mut W,
+        value: &JsonValue,
+        depth: usize,
+    ) -> io::Result<()> {
+        match value {
+            JsonValue::Null => write!(output, "{}null{}", NULL_COLOR, RESET),
+            JsonValue::Boolean(b) => write!(output, "{}{}{}", BOOLEAN_COLOR, b, RESET),
+            JsonValue::Number(n) => {
+                write!(output, "{}", NUMBER_COLOR)?;
+                serde_json::to_writer(&mut *output, n)?;
+                write!(output, "{}", RESET)
+            }
+            JsonValue::String(s) => self.write_string(output, s, STRING_COLOR),
+            JsonValue::Array(elems) => {
+                if elems.is_empty() {
+                    return output.write_all(b"[]");
+                }
+
+                output.write_all(b"[")?;
+
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        output.write_all(b",")?;
+                    }
+
+                    self.write_newline_indent(output, depth + 1)?;
+                    self.write_value(output, elem, depth + 1)?;
+                }
+
+                self.write_newline_indent(output, depth)?;
+                output.write_all(b"]")
+            }
+            JsonValue::Object(fields) => {
+                if fields.is_empty() {
+                    return output.write_all(b"{}");
+                }
+
+                output.write_all(b"{")?;
+
+                for (i, (key, field)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        output.write_all(b",")?;
+                    }
+
+                    self.write_newline_indent(output, depth + 1)?;
+                    self.write_string(output, key, KEY_COLOR)?;
+                    output.write_all(if self.indent.is_some() { b": " } else { b":" })?;
+                    self.write_value(output, field, depth + 1)?;
+                }
+
+                self.write_newline_indent(output, depth)?;
+                output.write_all(b"}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_format::write_json;
+    use crate::json_value::NumberVal;
+
+    fn sample() -> JsonValue {
+        JsonValue::Object(
+            vec![
+                (
+                    "a".to_string(),
+                    JsonValue::Array(vec![
+                        JsonValue::Number(NumberVal::Integer(1)),
+                        JsonValue::Boolean(true),
+                        JsonValue::Null,
+                    ]),
+                ),
+                ("b".to_string(), JsonValue::String("x".to_string())),
+                ("c".to_string(), JsonValue::Object(Default::default())),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    fn strip_colors(s: &str) -> String {
+        let mut acc = String::new();
+        let mut in_escape = false;
+
+        for c in s.chars() {
+            match c {
+                '\x1b' => in_escape = true,
+                'm' if in_escape => in_escape = false,
+                _ if in_escape => {}
+                _ => acc.push(c),
+            }
+        }
+
+        acc
+    }
+
+    #[test]
+    fn colorize_each_kind_of_value() {
+        let mut output = Vec::new();
+        write_colored(&mut output, &sample(), None, FormatOptions::default()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\x1b[1;34m\"a\"\x1b[0m:[\x1b[36m1\x1b[0m,\x1b[33mtrue\x1b[0m,\x1b[1;30mnull\x1b[0m],\
+             \x1b[1;34m\"b\"\x1b[0m:\x1b[32m\"x\"\x1b[0m,\x1b[1;34m\"c\"\x1b[0m:{}}"
+        );
+    }
+
+    #[test]
+    fn keep_plain_json_layout() {
+        for &indent in &[None, Some(&b"  "[..]), Some(&b"\t"[..])] {
+            let mut output = Vec::new();
+            write_colored(&mut output, &sample(), indent, FormatOptions::default()).unwrap();
+
+            let mut expected = Vec::new();
+            write_json(&mut expected, &sample(), indent, FormatOptions::default()).unwrap();
+            let expected = String::from_utf8(expected).unwrap();
+
+            assert_eq!(strip_colors(&String::from_utf8(output).unwrap()), expected);
+        }
+    }
+}
@@ -1,18 +1,56 @@
+use combine::easy::{Error, Errors, Info};
 use failure::Fail;
 
 use crate::json_path::JsonPath;
 use crate::json_value::JsonValue;
+use crate::unicode_stream::Location;
+use crate::validate::Violation;
 
 #[derive(Debug, Fail)]
 pub enum InitError {
     #[fail(display = "unable to open the file {}", filename)]
     UnableToOpenFile { filename: String },
 
-    #[fail(display = "syntax error in the query at position {}", position)]
-    WrongQuerySyntax { position: i32 },
+    #[fail(
+        display = "syntax error in the query at position {} : {}\n    {}\n    {}",
+        position, message, query, caret
+    )]
+    WrongQuerySyntax {
+        position: i32,
+        message: String,
+        query: String,
+        caret: String,
+    },
+
+    #[fail(
+        display = "syntax error in the path at position {} : {}\n    {}\n    {}",
+        position, message, path, caret
+    )]
+    WrongPathSyntax {
+        position: i32,
+        message: String,
+        path: String,
+        caret: String,
+    },
+
+    #[fail(
+        display = "the output filename {} must contain {{}} to be partitioned",
+        filename
+    )]
+    MissingPartitionPlaceholder { filename: String },
 
-    #[fail(display = "unknown stage : {}", unknown_name)]
-    StageUnknownName { unknown_name: String },
+    #[fail(
+        display = "{} query outputs given for {} additional queries",
+        outputs, queries
+    )]
+    TooManyQueryOutputs { outputs: usize, queries: usize },
+
+    #[fail(display = "unknown stage : {}{}", unknown_name, suggestion)]
+    StageUnknownName {
+        unknown_name: String,
+        // Empty, or the closest stage names
+        suggestion: String,
+    },
 
     #[fail(
         display = "wrong number of arguments for stage {}, expected {}, got {}",
@@ -31,6 +69,90 @@ pub enum InitError {
     StageWrongArgType { stage_name: String, arg_pos: usize },
 }
 
+impl InitError {
+    /// Syntax error in `query`, from the errors of its parser.
+    pub fn query_syntax(query: &str, errors: Errors<u8, &[u8], usize>) -> InitError {
+        InitError::WrongQuerySyntax {
+            position: errors.position as i32 + 1, // Columns start at 1
+            message: describe_errors(&errors),
+            query: query.to_string(),
+            caret: caret_under(query, errors.position),
+        }
+    }
+
+    /// Syntax error in `path`, from the errors of its parser.
+    pub fn path_syntax(path: &str, errors: Errors<u8, &[u8], usize>) -> InitError {
+        InitError::WrongPathSyntax {
+            position: errors.position as i32 + 1, // Columns start at 1
+            message: describe_errors(&errors),
+            path: path.to_string(),
+            caret: caret_under(path, errors.position),
+        }
+    }
+
+    /// Unknown stage, suggesting the known ones which are close enough to be typos.
+    pub fn stage_unknown_name(unknown_name: &str, known_names: &[&str]) -> InitError {
+        let distances = known_names
+            .iter()
+            .map(|name| (edit_distance(unknown_name, name), *name));
+        let threshold = std::cmp::max(1, unknown_name.chars().count() / 3);
+        let closest = distances.clone().map(|(d, _)| d).min().unwrap_or(0);
+
+        let suggestion = if closest > threshold {
+            String::new()
+        } else {
+            let names: Vec<_> = distances
+                .filter(|(d, _)| *d == closest)
+                .map(|(_, name)| name)
+                .collect();
+
+            format!(", did you mean {} ?", names.join(" or "))
+        };
+
+        InitError::StageUnknownName {
+            unknown_name: unknown_name.to_string(),
+            suggestion,
+        }
+    }
+}
+
+/// A caret under the character of `text` at the byte `position`.
+fn caret_under(text: &str, position: usize) -> String {
+    let prefix = text.as_bytes().get(..position).unwrap_or(text.as_bytes());
+
+    format!(
+        "{}^",
+        " ".repeat(String::from_utf8_lossy(prefix).chars().count())
+    )
+}
+
+/// Optimal string alignment distance between `a` and `b`, in characters : a Levenshtein
+/// distance where swapping two adjacent characters counts as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before_previous: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 0..a.len() {
+        let mut current = vec![i + 1];
+
+        for j in 0..b.len() {
+            let substitution = previous[j] + if a[i] == b[j] { 0 } else { 1 };
+            let mut distance = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                distance = distance.min(before_previous[j - 1] + 1);
+            }
+            current.push(distance);
+        }
+
+        before_previous = std::mem::replace(&mut previous, current);
+    }
+
+    previous[b.len()]
+}
+
 #[derive(Debug, Fail)]
 pub enum PipelineError {
     #[fail(display = "number expected at {} but got {}", path, value)]
@@ -42,6 +164,208 @@ pub enum PipelineError {
     #[fail(display = "missing value at {}", path)]
     MissingValue { path: JsonPath },
 
+    #[fail(display = "integer overflow in the sum of {}", path)]
+    SumOverflow { path: JsonPath },
+
+    #[fail(display = "unable to open the output file {}", filename)]
+    UnableToOpenOutput { filename: String },
+
     #[fail(display = "unable to write to output")]
     UnableToWriteOuptut,
 }
+
+impl PipelineError {
+    /// Whether the error comes from the item being ingested, and not from the pipeline itself.
+    pub fn is_item_error(&self) -> bool {
+        match self {
+            PipelineError::NotANumber { .. }
+            | PipelineError::NotAnObject { .. }
+            | PipelineError::MissingValue { .. } => true,
+            PipelineError::SumOverflow { .. }
+            | PipelineError::UnableToOpenOutput { .. }
+            | PipelineError::UnableToWriteOuptut => false,
+        }
+    }
+
+    /// Path of the part of the item causing the error, if any.
+    pub fn path(&self) -> Option<&JsonPath> {
+        match self {
+            PipelineError::NotANumber { path, .. } | PipelineError::MissingValue { path } => {
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Malformed records were skipped, the rest of the input being processed.
+#[derive(Debug, Fail)]
+#[fail(display = "{} malformed records skipped", count)]
+pub struct SkippedRecords {
+    pub count: u64,
+}
+
+/// Malformed input, located by its byte offset, line and column, and by the path
+/// of the value being parsed.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "invalid input at line {}, column {} (byte {}), in {} : {}\n    {}",
+    line, column, offset, path, message, excerpt
+)]
+pub struct InputError {
+    pub offset: u64,
+    pub line: u64,
+    pub column: u64,
+    pub path: JsonPath,
+    pub message: String,
+    pub excerpt: String,
+}
+
+impl InputError {
+    pub fn new(errors: Errors<u8, u8, u64>, location: Location, path: JsonPath) -> InputError {
+        InputError {
+            offset: location.offset,
+            line: location.line,
+            column: location.column,
+            path,
+            message: describe_errors(&errors.map_range(|b| [b])),
+            excerpt: location.excerpt,
+        }
+    }
+
+    /// Input which couldn't be read, like a malformed binary encoded input.
+    pub fn unreadable(message: String, location: Location, path: JsonPath) -> InputError {
+        InputError {
+            offset: location.offset,
+            line: location.line,
+            column: location.column,
+            path,
+            message,
+            excerpt: location.excerpt,
+        }
+    }
+
+    /// Input not conforming to RFC 8259, rejected in strict mode.
+    pub fn non_conforming(violation: Violation, location: Location, path: JsonPath) -> InputError {
+        InputError {
+            offset: location.offset,
+            line: location.line,
+            column: location.column,
+            path,
+            message: violation.message,
+            excerpt: location.excerpt,
+        }
+    }
+}
+
+/// Input not conforming to RFC 8259, found by its validation alone.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "non-conforming JSON at line {}, column {} (byte {}) : {}\n    {}",
+    line, column, offset, message, excerpt
+)]
+pub struct NonConformingInput {
+    pub offset: u64,
+    pub line: u64,
+    pub column: u64,
+    pub message: String,
+    pub excerpt: String,
+}
+
+impl NonConformingInput {
+    pub fn new(violation: Violation, location: Location) -> NonConformingInput {
+        NonConformingInput {
+            offset: location.offset,
+            line: location.line,
+            column: location.column,
+            message: violation.message,
+            excerpt: location.excerpt,
+        }
+    }
+}
+
+pub fn describe_bytes(bytes: &[u8]) -> String {
+    if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        format!("`{}`", String::from_utf8_lossy(bytes))
+    } else {
+        let hex: Vec<_> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+
+        format!("byte {}", hex.join(" "))
+    }
+}
+
+fn describe_info<R: AsRef<[u8]>>(info: &Info<u8, R>) -> String {
+    match info {
+        Info::Token(b) => describe_bytes(&[*b]),
+        Info::Range(r) => describe_bytes(r.as_ref()),
+        Info::Owned(s) => s.clone(),
+        Info::Borrowed(s) => s.to_string(),
+    }
+}
+
+/// Describe the errors of the parser in a sentence.
+fn describe_errors<R: AsRef<[u8]>, P>(errors: &Errors<u8, R, P>) -> String {
+    let mut unexpected = Vec::new();
+    let mut expected = Vec::new();
+    let mut messages = Vec::new();
+
+    for error in &errors.errors {
+        let (list, description) = match error {
+            Error::Unexpected(info) => (&mut unexpected, describe_info(info)),
+            // Whitespaces are allowed almost everywhere, listing them doesn't help
+            Error::Expected(Info::Borrowed("space"))
+            | Error::Expected(Info::Borrowed("whitespaces")) => continue,
+            Error::Expected(info) => (&mut expected, describe_info(info)),
+            Error::Message(info) => (&mut messages, describe_info(info)),
+            Error::Other(err) => (&mut messages, err.to_string()),
+        };
+
+        if !list.contains(&description) {
+            list.push(description);
+        }
+    }
+
+    let mut sentence = Vec::new();
+
+    if !unexpected.is_empty() {
+        sentence.push(format!("unexpected {}", unexpected.join(", ")));
+    }
+
+    if let Some((last, first)) = expected.split_last() {
+        if first.is_empty() {
+            sentence.push(format!("expected {}", last));
+        } else {
+            sentence.push(format!("expected {} or {}", first.join(", "), last));
+        }
+    }
+
+    sentence.append(&mut messages);
+    sentence.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_transposition_as_one_edit() {
+        assert_eq!(edit_distance("smu", "sum"), 1);
+        assert_eq!(edit_distance("mena", "mean"), 1);
+        assert_eq!(edit_distance("ad_field", "add_field"), 1);
+        assert_eq!(edit_distance("tee", "select"), 4);
+    }
+
+    #[test]
+    fn suggest_transposed_stage_name() {
+        let known_names = ["add_field", "mean", "select", "sum", "tee"];
+
+        assert_eq!(
+            InitError::stage_unknown_name("smu", &known_names).to_string(),
+            "unknown stage : smu, did you mean sum ?"
+        );
+        assert_eq!(
+            InitError::stage_unknown_name("mena", &known_names).to_string(),
+            "unknown stage : mena, did you mean mean ?"
+        );
+    }
+}
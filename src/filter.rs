@@ -3,6 +3,7 @@ use std::ops::Range;
 
 use crate::json_path::{JsonPath, JsonPathStep};
 
+#[derive(Clone)]
 pub enum BranchFilter {
     TextMatch(String),
     RegexMatch(Regex),
@@ -17,6 +18,7 @@ impl BranchFilter {
     }
 }
 
+#[derive(Clone)]
 pub enum ArrayFilter {
     ExactValue(u64),
     OneOf(Vec<u64>),
@@ -33,6 +35,7 @@ impl ArrayFilter {
     }
 }
 
+#[derive(Clone)]
 pub enum FilterPart {
     Branch(BranchFilter),
     Array(ArrayFilter),
@@ -59,6 +62,7 @@ impl FilterPart {
     }
 }
 
+#[derive(Clone)]
 pub enum Filter {
     All,
     Parts(Vec<FilterPart>),
@@ -66,6 +70,15 @@ pub enum Filter {
 }
 
 impl Filter {
+    /// Combine filters into one matching everything one of them matches.
+    pub fn union(mut filters: Vec<Filter>) -> Filter {
+        if filters.len() == 1 {
+            filters.pop().unwrap() // We can unwrap because filters.len() == 1
+        } else {
+            Filter::Union(filters)
+        }
+    }
+
     fn compare(&self, pos: &JsonPath, subpath: bool) -> bool {
         match self {
             Filter::All => true,
@@ -84,7 +97,7 @@ impl Filter {
 
                 true
             }
-            Filter::Union(ref filters) => filters.iter().any(|filter| filter.is_match(pos)),
+            Filter::Union(ref filters) => filters.iter().any(|filter| filter.compare(pos, subpath)),
         }
     }
 
@@ -95,4 +108,17 @@ impl Filter {
     pub fn is_subpath(&self, pos: &JsonPath) -> bool {
         self.compare(pos, true)
     }
+
+    /// Depth of the outermost value matched on the way to `pos`, if any.
+    pub fn match_depth(&self, pos: &JsonPath) -> Option<usize> {
+        match self {
+            Filter::All => Some(0),
+            Filter::Parts(ref parts) if self.is_match(pos) => Some(parts.len()),
+            Filter::Parts(_) => None,
+            Filter::Union(ref filters) => filters
+                .iter()
+                .filter_map(|filter| filter.match_depth(pos))
+                .min(),
+        }
+    }
 }
@@ -0,0 +1,219 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter, Serializer};
+
+use crate::json_value::JsonValue;
+
+/// Formatting options shared by all the JSON writers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions {
+    /// Sort the fields of the objects by key.
+    pub sort_keys: bool,
+    /// Escape every non-ASCII character of the strings.
+    pub ascii_output: bool,
+}
+
+/// Formatter escaping the non-ASCII characters of the strings as `\uXXXX` sequences,
+/// and delegating everything else to the inner formatter.
+struct AsciiFormatter<F: Formatter>(F);
+
+impl<F: Formatter> Formatter for AsciiFormatter<F> {
+    fn write_string_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        let mut start = 0;
+
+        for (i, c) in fragment.char_indices() {
+            if !c.is_ascii() {
+                writer.write_all(&fragment.as_bytes()[start..i])?;
+
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+
+                start = i + c.len_utf8();
+            }
+        }
+
+        writer.write_all(&fragment.as_bytes()[start..])
+    }
+
+    fn begin_array<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.begin_array(writer)
+    }
+
+    fn end_array<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.end_array(writer)
+    }
+
+    fn begin_array_value<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.begin_array_value(writer, first)
+    }
+
+    fn end_array_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.end_array_value(writer)
+    }
+
+    fn begin_object<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.begin_object(writer)
+    }
+
+    fn end_object<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.end_object(writer)
+    }
+
+    fn begin_object_key<W>(&mut self, writer: &mut W, first: bool) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.begin_object_key(writer, first)
+    }
+
+    fn end_object_key<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.end_object_key(writer)
+    }
+
+    fn begin_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.begin_object_value(writer)
+    }
+
+    fn end_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.0.end_object_value(writer)
+    }
+}
+
+fn serialize_with<W, F, T>(output: W, formatter: F, value: &T) -> io::Result<()>
+where
+    W: Write,
+    F: Formatter,
+    T: Serialize + ?Sized,
+{
+    value
+        .serialize(&mut Serializer::with_formatter(output, formatter))
+        .map_err(io::Error::from)
+}
+
+/// Write a value as JSON, compact if `indent` is `None`, else with one `indent` per depth level.
+///
+/// The keys are written in the order of the value, they have to be sorted beforehand if requested.
+pub fn write_json<W: Write + ?Sized>(
+    output: &mut W,
+    value: &JsonValue,
+    indent: Option<&[u8]>,
+    options: FormatOptions,
+) -> io::Result<()> {
+    match (indent, options.ascii_output) {
+        (None, false) => serialize_with(output, CompactFormatter, value),
+        (None, true) => serialize_with(output, AsciiFormatter(CompactFormatter), value),
+        (Some(indent), false) => {
+            serialize_with(output, PrettyFormatter::with_indent(indent), value)
+        }
+        (Some(indent), true) => serialize_with(
+            output,
+            AsciiFormatter(PrettyFormatter::with_indent(indent)),
+            value,
+        ),
+    }
+}
+
+/// Write a string as a quoted and escaped JSON string.
+pub fn write_json_string<W: Write + ?Sized>(
+    output: &mut W,
+    s: &str,
+    options: FormatOptions,
+) -> io::Result<()> {
+    if options.ascii_output {
+        serialize_with(output, AsciiFormatter(CompactFormatter), s)
+    } else {
+        serialize_with(output, CompactFormatter, s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_value::NumberVal;
+
+    fn sample() -> JsonValue {
+        JsonValue::Object(
+            vec![
+                ("b".to_string(), JsonValue::String("é😀".to_string())),
+                (
+                    "a".to_string(),
+                    JsonValue::Array(vec![JsonValue::Number(NumberVal::Integer(1))]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    fn to_string(value: &JsonValue, indent: Option<&[u8]>, options: FormatOptions) -> String {
+        let mut output = Vec::new();
+        write_json(&mut output, value, indent, options).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn write_with_custom_indent() {
+        assert_eq!(
+            to_string(&sample(), Some(b"\t"), FormatOptions::default()),
+            "{\n\t\"b\": \"é😀\",\n\t\"a\": [\n\t\t1\n\t]\n}"
+        );
+    }
+
+    #[test]
+    fn write_ascii_only() {
+        let options = FormatOptions {
+            ascii_output: true,
+            ..FormatOptions::default()
+        };
+
+        assert_eq!(
+            to_string(&sample(), None, options),
+            "{\"b\":\"\\u00e9\\ud83d\\ude00\",\"a\":[1]}"
+        );
+    }
+
+    #[test]
+    fn write_sorted_keys() {
+        let mut value = sample();
+        value.sort_keys();
+
+        assert_eq!(
+            to_string(&value, None, FormatOptions::default()),
+            "{\"a\":[1],\"b\":\"é😀\"}"
+        );
+    }
+}
@@ -28,7 +28,7 @@ impl JsonPathStep {
 ///
 /// A path is a sequence of steps from the root.
 /// Since a JSON document is a tree and a step can't go back, there is one and only one path for each document.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct JsonPath(Vec<JsonPathStep>);
 
 impl JsonPath {
@@ -82,6 +82,11 @@ impl JsonPath {
         self.0.pop();
     }
 
+    /// Create the path of `relative`, which is relative to this path.
+    pub fn join(&self, relative: &JsonPath) -> JsonPath {
+        JsonPath(self.0.iter().chain(relative.iter()).cloned().collect())
+    }
+
     /// Iterate over the steps of the path.
     pub fn iter(&self) -> std::slice::Iter<'_, JsonPathStep> {
         self.0.iter()
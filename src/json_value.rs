@@ -1,17 +1,82 @@
-use serde::Serialize;
-use std::collections::HashMap;
-use unicode_normalization::UnicodeNormalization;
+use indexmap::IndexMap;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::convert::TryFrom;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
 
 use crate::json_path::{JsonPath, JsonPathStep};
 
-#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
-#[serde(untagged)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum NumberVal {
     Integer(i64),
+    /// Integer above `i64::MAX`.
+    Unsigned(u64),
     Float(f64),
+    /// Number parsed from the input, kept as its original text so it is written back
+    /// unchanged. Its value is parsed from the text when it is used.
+    Exact(String),
+}
+
+impl NumberVal {
+    /// Smallest variant holding an integer exactly.
+    pub fn from_i128(i: i128) -> NumberVal {
+        if let Ok(i) = i64::try_from(i) {
+            NumberVal::Integer(i)
+        } else if let Ok(u) = u64::try_from(i) {
+            NumberVal::Unsigned(u)
+        } else {
+            NumberVal::Exact(i.to_string())
+        }
+    }
+
+    /// Whether the number is written as an integer, without fraction nor exponent.
+    pub fn is_integer(&self) -> bool {
+        match self {
+            NumberVal::Integer(_) | NumberVal::Unsigned(_) => true,
+            NumberVal::Float(_) => false,
+            NumberVal::Exact(digits) => !digits.contains(['.', 'e', 'E']),
+        }
+    }
+
+    /// Value of an integer, if it fits in 128 bits.
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            NumberVal::Integer(i) => Some(i128::from(*i)),
+            NumberVal::Unsigned(u) => Some(i128::from(*u)),
+            NumberVal::Float(_) => None,
+            NumberVal::Exact(digits) => digits.parse().ok(),
+        }
+    }
+
+    /// Nearest floating point value.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            NumberVal::Integer(i) => *i as f64,
+            NumberVal::Unsigned(u) => *u as f64,
+            NumberVal::Float(f) => *f,
+            NumberVal::Exact(digits) => digits.parse().unwrap_or(f64::NAN),
+        }
+    }
+}
+
+impl Serialize for NumberVal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            NumberVal::Integer(i) => serializer.serialize_i64(*i),
+            NumberVal::Unsigned(u) => serializer.serialize_u64(*u),
+            NumberVal::Float(f) => serializer.serialize_f64(*f),
+            // The raw values are written as they are by serde_json.
+            NumberVal::Exact(digits) => match RawValue::from_string(digits.clone()) {
+                Ok(raw) => raw.serialize(serializer),
+                Err(_) => serializer.serialize_f64(self.as_f64()),
+            },
+        }
+    }
 }
 
 /// Represents any possible value of a JSON document.
+///
+/// The fields of an object are kept in their order of appearance.
 #[derive(Serialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum JsonValue {
@@ -19,13 +84,37 @@ pub enum JsonValue {
     String(String),
     Number(NumberVal),
     Boolean(bool),
-    Object(HashMap<String, JsonValue>),
+    Object(IndexMap<String, JsonValue>),
     Array(Vec<JsonValue>),
 }
 
 impl JsonValue {
-    pub fn normalized_string(s: &str) -> JsonValue {
-        JsonValue::String(s.nfc().collect())
+    pub fn normalized_string(s: String) -> JsonValue {
+        // Most strings are already normalized, like all the ASCII ones, and are kept as they are.
+        if s.is_ascii() || is_nfc_quick(s.chars()) == IsNormalized::Yes {
+            JsonValue::String(s)
+        } else {
+            JsonValue::String(s.nfc().collect())
+        }
+    }
+
+    /// Sort the fields of the objects by key, recursively.
+    pub fn sort_keys(&mut self) {
+        match self {
+            JsonValue::Object(ref mut fields) => {
+                fields.sort_keys();
+
+                for field in fields.values_mut() {
+                    field.sort_keys();
+                }
+            }
+            JsonValue::Array(ref mut elems) => {
+                for elem in elems.iter_mut() {
+                    elem.sort_keys();
+                }
+            }
+            _ => {}
+        }
     }
 
     pub fn select<'a>(&'a self, path: &JsonPath) -> Option<&'a JsonValue> {
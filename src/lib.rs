@@ -2,35 +2,112 @@
 #![recursion_limit = "256"]
 
 mod args_parser;
+mod binary_input;
+mod colored_json;
 mod error;
 mod filter;
+mod json_format;
 mod json_path;
 mod json_value;
+mod ndjson;
+mod output;
 mod parse_and_keep;
+mod parse_and_skip;
 mod parse_and_throw;
 mod parse_basics;
 mod parse_query;
 mod parse_smart;
 mod pipeline;
 mod pipeline_builder;
+mod recovery;
 mod unicode_stream;
+mod validate;
 
+use combine::easy;
 use combine::parser::Parser;
 use std::convert::From;
 
 pub use crate::args_parser::ArgStruct;
-use crate::pipeline_builder::PipelineBuilder;
+use crate::error::InputError;
+pub use crate::error::SkippedRecords;
+use crate::ndjson::process_ndjson;
+use crate::pipeline_builder::{Input, PipelineBuilder};
+use crate::unicode_stream::{ScanStream, SliceStream};
+use crate::validate::{validate_input, SharedViolation};
+
+/// Parse the whole input, locating the error if it's malformed, or its violation of RFC 8259
+/// in strict mode.
+fn parse_input<I: ScanStream>(
+    pipeline_builder: &PipelineBuilder,
+    input: I,
+    violation: &SharedViolation,
+) -> Result<(), failure::Error> {
+    let (mut parser, state) = pipeline_builder.build_parser()?;
+    let mut input = easy::Stream(input);
+
+    if let Err(err) = parser.parse_stream(&mut input) {
+        // A failing pipeline stops the parsing with an error of its own
+        if let Some(error) = state.take_error() {
+            return Err(error.into());
+        }
+
+        let errors = err.into_inner().error;
+
+        // The input is cut at the violation, the parser can only fail on its own before it.
+        if let Some(violation) = violation.lock().unwrap().take() {
+            if errors.position >= violation.offset {
+                let location = input.0.locate(violation.offset);
+
+                return Err(InputError::non_conforming(violation, location, state.path()).into());
+            }
+        }
+
+        let location = input.0.locate(errors.position);
+
+        if let Some(message) = input.0.read_error() {
+            return Err(InputError::unreadable(message, location, state.path()).into());
+        }
+
+        return Err(InputError::new(errors, location, state.path()).into());
+    }
+
+    // The bytes before the violation can be valid on their own, the input is not.
+    if let Some(violation) = violation.lock().unwrap().take() {
+        let location = input.0.locate(violation.offset);
+
+        return Err(InputError::non_conforming(violation, location, state.path()).into());
+    }
+
+    // Only a valid input gets its outputs finished, like the atomic ones committed.
+    state.finish()?;
+
+    Ok(())
+}
 
 pub fn parse_from_args(args: ArgStruct) -> Result<(), failure::Error> {
-    // ParseError<Stream<ReadStream<Stdin>>>
     let pipeline_builder = PipelineBuilder::from(&args);
 
-    let stream = pipeline_builder.build_input_stream()?;
+    if args.validate {
+        return validate_input(pipeline_builder.build_input());
+    }
+
+    if args.ndjson {
+        return process_ndjson(&pipeline_builder);
+    }
+
+    let violation = SharedViolation::default();
 
-    let mut parser = pipeline_builder.build_parser()?;
+    match pipeline_builder.build_input_stream(&violation)? {
+        Input::Mapped(map) => {
+            // Like from a `ValidatingRead`, only the bytes before the violation are parsed.
+            let len = violation
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(map.len(), |violation| violation.offset as usize);
 
-    parser
-        .easy_parse(stream)
-        .map(|(output, _)| output)
-        .map_err(|err| err.into())
+            parse_input(&pipeline_builder, SliceStream::new(&map[..len]), &violation)
+        }
+        Input::Buffered(stream) => parse_input(&pipeline_builder, stream, &violation),
+    }
 }
@@ -1,12 +1,20 @@
 use exitfailure::ExitFailure;
 use sjq::parse_from_args;
 use sjq::ArgStruct;
+use sjq::SkippedRecords;
 use structopt::StructOpt;
 
-// TODO: Remove as much unwrap as possible in all src/ by panics and error messages (fail fast)
+/// Exit code when the input was processed, but some of its malformed records were skipped.
+const SKIPPED_RECORDS_EXIT_CODE: i32 = 2;
 
 fn main() -> Result<(), ExitFailure> {
     let args = ArgStruct::from_args();
 
-    Ok(parse_from_args(args)?)
+    match parse_from_args(args) {
+        Err(err) if err.downcast_ref::<SkippedRecords>().is_some() => {
+            eprintln!("Error: {}", err);
+            std::process::exit(SKIPPED_RECORDS_EXIT_CODE);
+        }
+        result => Ok(result?),
+    }
 }
@@ -0,0 +1,675 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use combine::parser::Parser;
+
+use crate::error::{InitError, InputError, PipelineError};
+use crate::filter::Filter;
+use crate::json_path::JsonPath;
+use crate::json_value::JsonValue;
+use crate::parse_basics::PARSER_STACK_SIZE;
+use crate::parse_query::{build_stages, StageSpec};
+use crate::parse_smart::{json_stream_smart, ParserOptions, ParserState};
+use crate::pipeline::{
+    DeadLetter, DeadLetters, PartialAggregate, Pipeline, SharedDeadLetterSink, SharedItemPath,
+};
+use crate::pipeline_builder::PipelineBuilder;
+use crate::recovery::{ErrorPolicy, RecordRejecter, RejectedRecord};
+use crate::unicode_stream::{Location, SliceStream};
+use crate::validate::Validator;
+
+/// Minimal size of the batches of lines sent to the workers.
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// Max number of batches read but not ingested yet, for each worker.
+const BATCHES_IN_FLIGHT_PER_WORKER: usize = 4;
+
+/// Items ingested by the queries of a worker, in order, with the index of their query
+/// and their path in the line if the dead letters need it.
+type CollectedItems = Rc<RefCell<Vec<(usize, JsonValue, Option<JsonPath>)>>>;
+
+/// Collects what the queries of a worker output while parsing, to send it to the main thread.
+#[derive(Default)]
+struct Collector {
+    items: CollectedItems,
+    // Only if the items failing a stage are set aside
+    dead_letters: Option<Rc<RefCell<Vec<DeadLetter>>>>,
+    item_path: SharedItemPath,
+}
+
+impl Collector {
+    fn new(dead_letters: bool) -> Collector {
+        Collector {
+            items: CollectedItems::default(),
+            dead_letters: if dead_letters {
+                Some(Rc::default())
+            } else {
+                None
+            },
+            item_path: SharedItemPath::default(),
+        }
+    }
+
+    /// Path of the item being ingested, tracked only for the dead letters.
+    fn item_path(&self) -> Option<SharedItemPath> {
+        self.dead_letters.as_ref().map(|_| self.item_path.clone())
+    }
+
+    /// Take what was collected since the last time.
+    fn take(&self) -> ParsedBatch {
+        ParsedBatch {
+            items: self.items.replace(Vec::new()),
+            rejected: Vec::new(),
+            dead_letters: self
+                .dead_letters
+                .as_ref()
+                .map_or_else(Vec::new, |dead_letters| dead_letters.replace(Vec::new())),
+        }
+    }
+}
+
+/// The part of a query run by the workers : its filter and its first stages.
+pub struct WorkerRoute {
+    filter: Filter,
+    stages: Vec<StageSpec>,
+}
+
+impl WorkerRoute {
+    pub fn new(filter: Filter, stages: Vec<StageSpec>) -> Result<WorkerRoute, InitError> {
+        let route = WorkerRoute { filter, stages };
+
+        // Built once here to report its errors, so the workers can't fail to build it.
+        route.build(0, &Collector::default())?;
+
+        Ok(route)
+    }
+
+    fn build(
+        &self,
+        index: usize,
+        collector: &Collector,
+    ) -> Result<(Filter, Box<dyn Pipeline>), InitError> {
+        let output = Box::new(CollectStage {
+            route: index,
+            items: collector.items.clone(),
+            item_path: collector.item_path(),
+        });
+        let dead_letters = collector
+            .dead_letters
+            .clone()
+            .map(|dead_letters| DeadLetters {
+                sink: dead_letters as SharedDeadLetterSink,
+                item_path: collector.item_path.clone(),
+            });
+        let pipeline = build_stages(
+            output,
+            &self.stages,
+            &|filename| {
+                Err(InitError::UnableToOpenFile {
+                    filename: filename.to_string(),
+                })
+            },
+            dead_letters.as_ref(),
+        )?;
+
+        Ok((self.filter.clone(), pipeline))
+    }
+}
+
+/// Collects the items of a query of a worker, to send them to the main thread.
+struct CollectStage {
+    route: usize,
+    items: CollectedItems,
+    item_path: Option<SharedItemPath>,
+}
+
+impl Pipeline for CollectStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        let path = self
+            .item_path
+            .as_ref()
+            .map(|item_path| item_path.borrow().clone());
+        self.items.borrow_mut().push((self.route, item, path));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        Ok(())
+    }
+}
+
+/// Whole lines of the input, with the position and the line of their first byte in the input.
+struct Batch {
+    index: usize,
+    offset: u64,
+    line: u64,
+    bytes: Vec<u8>,
+}
+
+/// The items of the queries of a batch, its malformed lines skipped,
+/// and the items which failed a stage.
+#[derive(Default)]
+struct ParsedBatch {
+    items: Vec<(usize, JsonValue, Option<JsonPath>)>,
+    rejected: Vec<RejectedRecord>,
+    dead_letters: Vec<DeadLetter>,
+}
+
+enum WorkerMessage {
+    /// The parsed batch, or the error which stopped its parsing.
+    Items(usize, Result<ParsedBatch, failure::Error>),
+    /// The states of the aggregating stages of the queries, sent once the input is done.
+    Partials(Vec<Option<PartialAggregate>>),
+}
+
+/// Sends the input to the workers by batches of lines, taking a credit for each batch
+/// so the batches waiting to be ingested in order don't pile up.
+///
+/// Returns the number of batches sent.
+fn read_batches(
+    input: Box<dyn Read + Send>,
+    batches: SyncSender<Batch>,
+    credits: Receiver<()>,
+) -> io::Result<usize> {
+    let mut input = BufReader::new(input);
+    let mut index = 0;
+    let mut offset = 0;
+    let mut line = 1;
+
+    loop {
+        let mut bytes = Vec::with_capacity(BATCH_SIZE);
+        let mut line_count = 0;
+        while bytes.len() < BATCH_SIZE && input.read_until(b'\n', &mut bytes)? > 0 {
+            line_count += 1;
+        }
+
+        if bytes.is_empty() {
+            return Ok(index);
+        }
+
+        let len = bytes.len() as u64;
+        if credits.recv().is_err()
+            || batches
+                .send(Batch {
+                    index,
+                    offset,
+                    line,
+                    bytes,
+                })
+                .is_err()
+        {
+            // The workers or the main thread stopped on an error
+            return Ok(index);
+        }
+
+        index += 1;
+        offset += len;
+        line += line_count;
+    }
+}
+
+/// Parses `bytes`, starting at `offset` and `line` in the input, into the items of the queries.
+fn parse_bytes(
+    state: &ParserState,
+    collector: &Collector,
+    max_text_length: usize,
+    bytes: &[u8],
+    offset: u64,
+    line: u64,
+) -> Result<ParsedBatch, failure::Error> {
+    // In strict mode, only the bytes before the violation are parsed.
+    let violation = Some(bytes)
+        .filter(|_| state.is_strict())
+        .and_then(|bytes| Validator::check(bytes).err());
+    let valid_len = violation
+        .as_ref()
+        .map_or(bytes.len(), |violation| violation.offset as usize);
+
+    let result = json_stream_smart(state.clone(), max_text_length)
+        .easy_parse(SliceStream::new(&bytes[..valid_len]));
+
+    if let (Ok(_), None) = (&result, &violation) {
+        return Ok(collector.take());
+    }
+
+    // Forget what was parsed before the error
+    collector.take();
+    let path = state.path();
+    state.reset_path();
+
+    if let Some(error) = state.take_error() {
+        return Err(error.into());
+    }
+
+    let errors = match (result, violation) {
+        (Err(errors), Some(ref violation)) if errors.position < violation.offset => errors,
+        (_, Some(violation)) => {
+            let location = Location::in_bytes(bytes, valid_len, offset, line, offset);
+
+            return Err(InputError::non_conforming(violation, location, path).into());
+        }
+        (Err(errors), None) => errors,
+        (Ok(_), None) => unreachable!("the batch is parsed"),
+    };
+
+    let location = Location::in_bytes(bytes, errors.position as usize, offset, line, offset);
+
+    Err(InputError::new(errors, location, path).into())
+}
+
+/// Parses the lines of a batch one by one, rejecting the malformed ones.
+fn parse_records(
+    state: &ParserState,
+    collector: &Collector,
+    max_text_length: usize,
+    batch: &Batch,
+) -> Result<ParsedBatch, failure::Error> {
+    let mut parsed = ParsedBatch::default();
+    let mut offset = batch.offset;
+    let records = batch.bytes.split_inclusive(|&b| b == b'\n');
+
+    for (line, record) in (batch.line..).zip(records) {
+        // Without its newline, so an unfinished line fails on its own line
+        let text = record.strip_suffix(b"\n").unwrap_or(record);
+
+        match parse_bytes(state, collector, max_text_length, text, offset, line) {
+            Ok(mut record_parsed) => {
+                parsed.items.append(&mut record_parsed.items);
+                parsed.dead_letters.append(&mut record_parsed.dead_letters);
+            }
+            Err(error) => parsed.rejected.push(RejectedRecord {
+                error: error.downcast::<InputError>()?,
+                raw: record.to_vec(),
+            }),
+        }
+
+        offset += record.len() as u64;
+    }
+
+    Ok(parsed)
+}
+
+fn run_worker(
+    routes: &[WorkerRoute],
+    options: ParserOptions,
+    max_text_length: usize,
+    policy: ErrorPolicy,
+    dead_letters: bool,
+    batches: &Mutex<Receiver<Batch>>,
+    messages: Sender<WorkerMessage>,
+) {
+    let collector = Collector::new(dead_letters);
+    let state = ParserState::new(
+        routes
+            .iter()
+            .enumerate()
+            .map(|(index, route)| route.build(index, &collector).unwrap()) // Already built once by WorkerRoute::new
+            .collect(),
+        options,
+        collector.item_path(),
+    );
+
+    loop {
+        let batch = match batches.lock() {
+            Ok(batches) => batches.recv(),
+            // Another worker panicked, which the main thread reports when joining it.
+            Err(_) => return,
+        };
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(_) => break, // The input is done
+        };
+
+        let parsed = parse_bytes(
+            &state,
+            &collector,
+            max_text_length,
+            &batch.bytes,
+            batch.offset,
+            batch.line,
+        );
+        // The workers only run stages without state when the malformed lines are skipped,
+        // so the whole batch can be parsed again line by line.
+        let result = match parsed {
+            Err(ref error)
+                if policy != ErrorPolicy::Fail && error.downcast_ref::<InputError>().is_some() =>
+            {
+                parse_records(&state, &collector, max_text_length, &batch)
+            }
+            parsed => parsed,
+        };
+
+        if messages
+            .send(WorkerMessage::Items(batch.index, result))
+            .is_err()
+        {
+            return; // The main thread stopped on an error
+        }
+    }
+
+    let _ = messages.send(WorkerMessage::Partials(state.take_partials()));
+}
+
+/// Ingests the items of the batches in the order of the input, and merges the states
+/// of the aggregating stages of the workers. A credit is given back to the reader
+/// for each batch ingested.
+///
+/// Returns the number of batches ingested.
+fn ingest_in_order(
+    messages: Receiver<WorkerMessage>,
+    credits: &SyncSender<()>,
+    pipelines: &mut [Box<dyn Pipeline>],
+    rejecter: &mut RecordRejecter,
+    dead_letters: Option<&DeadLetters>,
+) -> Result<usize, failure::Error> {
+    let mut pending = BTreeMap::new();
+    let mut next_index = 0;
+
+    for message in messages {
+        match message {
+            WorkerMessage::Items(index, result) => {
+                pending.insert(index, result);
+
+                while let Some(result) = pending.remove(&next_index) {
+                    let parsed = result?;
+
+                    for record in parsed.rejected {
+                        rejecter.reject(record)?;
+                    }
+
+                    if let Some(dead_letters) = dead_letters {
+                        for letter in parsed.dead_letters {
+                            dead_letters.sink.borrow_mut().send(letter)?;
+                        }
+                    }
+
+                    for (route, item, path) in parsed.items {
+                        if let (Some(dead_letters), Some(path)) = (dead_letters, path) {
+                            *dead_letters.item_path.borrow_mut() = path;
+                        }
+                        pipelines[route].ingest(item)?;
+                    }
+
+                    next_index += 1;
+                    // The reader may be done, not needing it anymore.
+                    let _ = credits.send(());
+                }
+            }
+            WorkerMessage::Partials(partials) => {
+                for (pipeline, partial) in pipelines.iter_mut().zip(partials) {
+                    if let Some(partial) = partial {
+                        pipeline.merge_partial(partial);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(next_index)
+}
+
+/// Processes a newline-delimited JSON input, parsing batches of lines in parallel
+/// and outputting their items in the order of the input.
+pub fn process_ndjson(builder: &PipelineBuilder) -> Result<(), failure::Error> {
+    let dead_letters = builder.build_dead_letters()?;
+    let (routes, mut pipelines): (Vec<_>, Vec<_>) = builder
+        .build_split_routes(dead_letters.as_ref())?
+        .into_iter()
+        .unzip();
+    let routes = Arc::new(routes);
+    let options = builder.build_parser_options();
+    let max_text_length = builder.max_text_length();
+    let thread_count = builder.build_thread_count();
+    let policy = builder.error_policy();
+    let collects_dead_letters = dead_letters.is_some();
+    let mut rejecter = builder.build_record_rejecter()?;
+
+    let (batch_sender, batch_receiver) = sync_channel(2 * thread_count);
+    let batch_receiver = Arc::new(Mutex::new(batch_receiver));
+    let (message_sender, messages) = channel();
+    let window = BATCHES_IN_FLIGHT_PER_WORKER * thread_count;
+    let (credit_sender, credits) = sync_channel(window);
+    for _ in 0..window {
+        credit_sender.send(())?;
+    }
+
+    let input = builder.build_input();
+    let reader = thread::spawn(move || read_batches(input, batch_sender, credits));
+    let workers: Vec<_> = (0..thread_count)
+        .map(|_| {
+            let routes = routes.clone();
+            let batch_receiver = batch_receiver.clone();
+            let message_sender = message_sender.clone();
+
+            thread::Builder::new()
+                .stack_size(PARSER_STACK_SIZE)
+                .spawn(move || {
+                    run_worker(
+                        &routes,
+                        options,
+                        max_text_length,
+                        policy,
+                        collects_dead_letters,
+                        &batch_receiver,
+                        message_sender,
+                    )
+                })
+        })
+        .collect::<Result<_, _>>()?;
+    drop(message_sender);
+
+    // On error, the threads are left behind to stop on their own.
+    let ingested_count = ingest_in_order(
+        messages,
+        &credit_sender,
+        &mut pipelines,
+        &mut rejecter,
+        dead_letters.as_ref(),
+    )?;
+
+    for worker in workers {
+        if let Err(panic) = worker.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+    let batch_count = match reader.join() {
+        Ok(result) => result?,
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    // Every worker is done, so every batch was ingested.
+    debug_assert_eq!(ingested_count, batch_count);
+
+    for pipeline in &mut pipelines {
+        pipeline.finish()?;
+    }
+    if let Some(dead_letters) = dead_letters {
+        dead_letters.sink.borrow_mut().flush()?;
+    }
+
+    rejecter.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_value::NumberVal;
+    use crate::parse_and_skip::Strictness;
+    use crate::parse_smart::PathOutput;
+    use crate::pipeline::{SumAcc, SumStage};
+    use crate::pipeline_builder::StageArg;
+
+    fn number(i: i64) -> JsonValue {
+        JsonValue::Number(NumberVal::Integer(i))
+    }
+
+    fn parsed(items: Vec<(usize, JsonValue)>) -> ParsedBatch {
+        ParsedBatch {
+            items: items
+                .into_iter()
+                .map(|(route, item)| (route, item, None))
+                .collect(),
+            ..ParsedBatch::default()
+        }
+    }
+
+    #[test]
+    fn ingest_batches_in_input_order() {
+        let items = CollectedItems::default();
+        let mut pipelines: Vec<Box<dyn Pipeline>> = vec![
+            Box::new(CollectStage {
+                route: 0,
+                items: items.clone(),
+                item_path: None,
+            }),
+            Box::new(SumStage::new(
+                Box::new(CollectStage {
+                    route: 1,
+                    items: items.clone(),
+                    item_path: None,
+                }),
+                JsonPath::root(),
+                false,
+            )),
+        ];
+
+        let (sender, messages) = channel();
+        let batches = vec![
+            WorkerMessage::Items(2, Ok(parsed(vec![(0, number(4))]))),
+            WorkerMessage::Partials(vec![
+                None,
+                Some(PartialAggregate::Sum(Some(SumAcc::Integer(3)))),
+            ]),
+            WorkerMessage::Items(1, Ok(parsed(vec![(0, number(2)), (0, number(3))]))),
+            WorkerMessage::Items(0, Ok(parsed(vec![(0, number(1))]))),
+            WorkerMessage::Partials(vec![
+                None,
+                Some(PartialAggregate::Sum(Some(SumAcc::Integer(4)))),
+            ]),
+        ];
+        for message in batches {
+            sender.send(message).unwrap();
+        }
+        drop(sender);
+
+        let (credit_sender, credits) = sync_channel(3);
+        let mut rejecter = RecordRejecter::new(ErrorPolicy::Fail, None);
+        assert_eq!(
+            ingest_in_order(
+                messages,
+                &credit_sender,
+                &mut pipelines,
+                &mut rejecter,
+                None
+            )
+            .unwrap(),
+            3
+        );
+        assert_eq!(credits.try_iter().count(), 3);
+        for pipeline in &mut pipelines {
+            pipeline.finish().unwrap();
+        }
+
+        assert_eq!(
+            items.replace(Vec::new()),
+            vec![
+                (0, number(1), None),
+                (0, number(2), None),
+                (0, number(3), None),
+                (0, number(4), None),
+                (1, number(7), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_batches_only_with_credits() {
+        let input = b"[1, 2]\n".repeat(BATCH_SIZE);
+        let (batch_sender, batches) = sync_channel(10);
+        let (credit_sender, credits) = sync_channel(2);
+        credit_sender.send(()).unwrap();
+        credit_sender.send(()).unwrap();
+        drop(credit_sender);
+
+        let sent = read_batches(Box::new(io::Cursor::new(input)), batch_sender, credits).unwrap();
+
+        assert_eq!(sent, 2);
+        let indexes: Vec<_> = batches.try_iter().map(|batch| batch.index).collect();
+        assert_eq!(indexes, vec![0, 1]);
+    }
+
+    #[test]
+    fn reject_malformed_lines_only() {
+        let collector = Collector::new(true);
+        let add_field = (
+            "add_field".to_string(),
+            vec![
+                StageArg::String("k".to_string()),
+                StageArg::String("v".to_string()),
+            ],
+        );
+        let route = WorkerRoute::new(Filter::All, vec![add_field]).unwrap();
+        let options = ParserOptions {
+            path_output: PathOutput::Value,
+            stream: false,
+            skip_strictness: Strictness::Full,
+            arbitrary_precision: false,
+            strict: false,
+        };
+        let state = ParserState::new(
+            vec![route.build(0, &collector).unwrap()],
+            options,
+            collector.item_path(),
+        );
+        let batch = Batch {
+            index: 0,
+            offset: 100,
+            line: 10,
+            bytes: b"{}\n[{}, tru]\n3 {\"a\": 4}\n[5,\n".to_vec(),
+        };
+
+        let parsed = parse_records(&state, &collector, 64, &batch).unwrap();
+
+        let items: Vec<_> = parsed
+            .items
+            .iter()
+            .map(|(route, item, _)| (*route, item.to_string()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (0, r#"{"k":"v"}"#.to_string()),
+                (0, r#"{"a":4,"k":"v"}"#.to_string())
+            ]
+        );
+        let rejected: Vec<_> = parsed
+            .rejected
+            .iter()
+            .map(|record| (record.error.offset, record.error.line, &record.raw[..]))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![(108, 11, &b"[{}, tru]\n"[..]), (127, 13, &b"[5,\n"[..])]
+        );
+        let dead_letters: Vec<_> = parsed
+            .dead_letters
+            .iter()
+            .map(|letter| {
+                (
+                    letter.stage.as_str(),
+                    letter.path.to_string(),
+                    letter.item.to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            dead_letters,
+            vec![("add_field", ".".to_string(), "3".to_string())]
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Stdout, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use flate2::write::GzEncoder;
+
+/// Writer of an output, which may have to be finalized once everything is written.
+pub trait OutputWrite: Write {
+    /// Flush the output and write its trailing data, if any.
+    /// Nothing can be written after this.
+    fn finish_output(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl OutputWrite for File {}
+
+impl OutputWrite for Stdout {}
+
+impl OutputWrite for Vec<u8> {}
+
+pub fn open_output_file(filename: &str, append: bool, force_new: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(!append)
+        .append(append)
+        .create(true)
+        .create_new(force_new)
+        .open(filename)
+}
+
+/// Returns the filename of a rotated output file, its index being inserted before the extension.
+///
+/// `out.json` becomes `out.0001.json` for the first file, `out` becomes `out.0001`.
+pub fn rotated_filename(filename: &str, index: u64) -> String {
+    let name_start = filename
+        .rfind(std::path::is_separator)
+        .map_or(0, |sep_pos| sep_pos + 1);
+
+    match filename[name_start..].rfind('.') {
+        Some(dot_pos) if dot_pos > 0 => {
+            let (stem, extension) = filename.split_at(name_start + dot_pos);
+
+            format!("{}.{:04}{}", stem, index, extension)
+        }
+        _ => format!("{}.{:04}", filename, index),
+    }
+}
+
+/// Returns the filename of the temporary file of an atomic write, in the same directory as
+/// the target file so it can be renamed over it.
+pub fn temporary_filename(filename: &str) -> String {
+    let name_start = filename
+        .rfind(std::path::is_separator)
+        .map_or(0, |sep_pos| sep_pos + 1);
+    let (dir, name) = filename.split_at(name_start);
+
+    format!("{}.{}.{}.tmp", dir, name, std::process::id())
+}
+
+/// Syncs a completely written temporary file to the disk, and renames it over its target file.
+pub fn commit_temporary_file(temporary_filename: &str, filename: &str) -> io::Result<()> {
+    File::open(temporary_filename)?.sync_all()?;
+
+    fs::rename(temporary_filename, filename)
+}
+
+/// Writer counting the bytes written through it into a shared counter.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W, count: Rc<Cell<u64>>) -> CountingWriter<W> {
+        CountingWriter { inner, count }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.set(self.count.get() + written as u64);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> OutputWrite for CountingWriter<W> {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Infers the compression of a file from its extension.
+    pub fn from_filename(filename: &str) -> Option<Compression> {
+        if filename.ends_with(".gz") {
+            Some(Compression::Gzip)
+        } else if filename.ends_with(".zst") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression : {}", s)),
+        }
+    }
+}
+
+/// Writer compressing its content before writing it to the inner writer.
+pub enum CompressedWriter<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(inner: W, compression: Compression) -> io::Result<CompressedWriter<W>> {
+        match compression {
+            Compression::Gzip => Ok(CompressedWriter::Gzip(GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            ))),
+            Compression::Zstd => Ok(CompressedWriter::Zstd(zstd::Encoder::new(
+                inner,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?)),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.write(buf),
+            CompressedWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(encoder) => encoder.flush(),
+            CompressedWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl<W: Write> OutputWrite for CompressedWriter<W> {
+    fn finish_output(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            CompressedWriter::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_index_before_extension() {
+        assert_eq!(rotated_filename("out.json", 1), "out.0001.json");
+        assert_eq!(
+            rotated_filename("dir.d/out.tar.gz", 12),
+            "dir.d/out.tar.0012.gz"
+        );
+        assert_eq!(rotated_filename("dir.d/out", 3), "dir.d/out.0003");
+        assert_eq!(rotated_filename(".hidden", 10000), ".hidden.10000");
+    }
+
+    #[test]
+    fn hide_temporary_file_next_to_target() {
+        let suffix = format!(".{}.tmp", std::process::id());
+
+        assert_eq!(
+            temporary_filename("out.json"),
+            format!(".out.json{}", suffix)
+        );
+        assert_eq!(
+            temporary_filename("dir/out.json"),
+            format!("dir/.out.json{}", suffix)
+        );
+    }
+
+    #[test]
+    fn finish_compressed_streams() {
+        use std::io::Read;
+
+        let mut writer = CompressedWriter::new(Vec::new(), Compression::Gzip).unwrap();
+        writer.write_all(b"{\"a\":1}\n").unwrap();
+        writer.finish_output().unwrap();
+
+        let compressed = match writer {
+            CompressedWriter::Gzip(encoder) => encoder.finish().unwrap(),
+            CompressedWriter::Zstd(_) => unreachable!(),
+        };
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, "{\"a\":1}\n");
+
+        let mut writer = CompressedWriter::new(Vec::new(), Compression::Zstd).unwrap();
+        writer.write_all(b"{\"a\":1}\n").unwrap();
+        writer.finish_output().unwrap();
+
+        let compressed = match writer {
+            CompressedWriter::Zstd(encoder) => encoder.finish().unwrap(),
+            CompressedWriter::Gzip(_) => unreachable!(),
+        };
+
+        assert_eq!(
+            zstd::decode_all(&compressed[..]).unwrap(),
+            b"{\"a\":1}\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn count_written_bytes() {
+        let count = Rc::new(Cell::new(0));
+        let mut writer = CountingWriter::new(Vec::new(), count.clone());
+
+        write!(writer, "abc").unwrap();
+        writer.write_all(b"de").unwrap();
+
+        assert_eq!(count.get(), 5);
+    }
+}
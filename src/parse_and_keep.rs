@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use indexmap::IndexMap;
 use std::iter::FromIterator;
 
 use combine::error::ParseError;
@@ -11,27 +11,30 @@ use combine::stream::Stream;
 use combine::{combine_parse_partial, combine_parser_impl, parse_mode, parser};
 
 use crate::json_value::{JsonValue, NumberVal};
-use crate::parse_basics::{keyword_expr, lex, number_expr, string_expr, string_lex, token_lex};
+use crate::parse_basics::{
+    keyword_expr, lex, number_expr, opening_lex, scan_string_expr, scan_string_lex, token_lex,
+};
+use crate::unicode_stream::ScanStream;
 
-fn keep_number<I>() -> impl Parser<Input = I, Output = JsonValue>
+fn keep_number<I>(arbitrary_precision: bool) -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    number_expr().map(|n: NumberVal| JsonValue::Number(n))
+    number_expr(arbitrary_precision).map(|n: NumberVal| JsonValue::Number(n))
 }
 
 fn keep_string<I>(max_length: usize) -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    string_expr(max_length).map(|s: String| JsonValue::normalized_string(&s))
+    scan_string_expr(max_length).map(JsonValue::normalized_string)
 }
 
 fn keep_keyword<I>() -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let null_val = keyword_expr("null").map(|_| JsonValue::Null);
@@ -43,81 +46,115 @@ where
     choice((null_val, true_val, false_val))
 }
 
-fn keep_array_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = JsonValue>
+/// Parse a string, a number or a keyword.
+pub fn keep_scalar<I>(
+    max_text_length: usize,
+    arbitrary_precision: bool,
+) -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice((
+        keep_string(max_text_length),
+        keep_number(arbitrary_precision),
+        keep_keyword(),
+    ))
+}
+
+fn keep_array_<I>(
+    max_text_length: usize,
+    arbitrary_precision: bool,
+    depth: usize,
+) -> impl Parser<Input = I, Output = JsonValue>
+where
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     between(
-        token_lex('['),
-        token(']'),
-        sep_by::<Vec<JsonValue>, _, _>(lex(keep_json(max_text_length)), token_lex(',')),
+        opening_lex(b'[', depth),
+        token(b']'),
+        sep_by::<Vec<JsonValue>, _, _>(
+            lex(keep_json(max_text_length, arbitrary_precision, depth + 1)),
+            token_lex(b','),
+        ),
     )
     .map(|v| JsonValue::Array(v))
 }
 
 parser! {
-    fn keep_array[I](max_text_length: usize)(I) -> JsonValue
-    where [I: Stream<Item = char>]
+    fn keep_array[I](max_text_length: usize, arbitrary_precision: bool, depth: usize)(I) -> JsonValue
+    where [I: ScanStream]
     {
-        keep_array_(*max_text_length)
+        keep_array_(*max_text_length, *arbitrary_precision, *depth)
     }
 }
 
-fn keep_object_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = JsonValue>
+fn keep_object_<I>(
+    max_text_length: usize,
+    arbitrary_precision: bool,
+    depth: usize,
+) -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    let field = string_lex(max_text_length)
-        .skip(token_lex(':'))
-        .and(lex(keep_json(max_text_length)));
+    let field = scan_string_lex(max_text_length)
+        .skip(token_lex(b':'))
+        .and(lex(keep_json(
+            max_text_length,
+            arbitrary_precision,
+            depth + 1,
+        )));
 
     let expr = between(
-        token_lex('{'),
-        token('}'),
-        sep_by::<Vec<(String, JsonValue)>, _, _>(field, token_lex(',')),
+        opening_lex(b'{', depth),
+        token(b'}'),
+        sep_by::<Vec<(String, JsonValue)>, _, _>(field, token_lex(b',')),
     );
 
-    expr.map(|v| JsonValue::Object(HashMap::from_iter(v)))
+    expr.map(|v| JsonValue::Object(IndexMap::from_iter(v)))
 }
 
 parser! {
-    fn keep_object[I](max_text_length: usize)(I) -> JsonValue
-    where [I: Stream<Item = char>]
+    fn keep_object[I](max_text_length: usize, arbitrary_precision: bool, depth: usize)(I) -> JsonValue
+    where [I: ScanStream]
     {
-        keep_object_(*max_text_length)
+        keep_object_(*max_text_length, *arbitrary_precision, *depth)
     }
 }
 
-fn keep_json_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = JsonValue>
+fn keep_json_<I>(
+    max_text_length: usize,
+    arbitrary_precision: bool,
+    depth: usize,
+) -> impl Parser<Input = I, Output = JsonValue>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     choice((
         keep_string(max_text_length),
-        keep_number(),
+        keep_number(arbitrary_precision),
         keep_keyword(),
-        keep_array(max_text_length),
-        keep_object(max_text_length),
+        keep_array(max_text_length, arbitrary_precision, depth),
+        keep_object(max_text_length, arbitrary_precision, depth),
     ))
 }
 
 parser! {
-    pub fn keep_json[I](max_text_length: usize)(I) -> JsonValue
-    where [I: Stream<Item = char>]
+    /// Parse a value nested in `depth` arrays and objects.
+    pub fn keep_json[I](max_text_length: usize, arbitrary_precision: bool, depth: usize)(I) -> JsonValue
+    where [I: ScanStream]
     {
-        keep_json_(*max_text_length)
+        keep_json_(*max_text_length, *arbitrary_precision, *depth)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use combine::stream::buffered::BufferedStream;
-    use combine::stream::state::State;
-    use combine::stream::IteratorStream;
+    use crate::unicode_stream::ReadStream;
 
     #[test]
     fn parse_short_complex() {
@@ -158,7 +195,16 @@ mod tests {
             .collect(),
         );
 
-        let stream = BufferedStream::new(State::new(IteratorStream::new(expr.chars())), 1000);
-        assert_eq!(keep_json(1000).parse(stream).unwrap().0, expected);
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert_eq!(keep_json(1000, false, 0).parse(stream).unwrap().0, expected);
+    }
+
+    #[test]
+    fn parse_normalized_strings() {
+        let expr = "[\"caf\u{e9}\", \"cafe\u{301}\", \"cafe\\u0301\"]";
+        let expected = JsonValue::Array(vec![JsonValue::String("caf\u{e9}".to_string()); 3]);
+
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert_eq!(keep_json(1000, false, 0).parse(stream).unwrap().0, expected);
     }
 }
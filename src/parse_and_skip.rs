@@ -0,0 +1,249 @@
+use std::str::FromStr;
+
+use combine::error::{Consumed, ParseError, StreamError};
+use combine::parser::function::parser;
+use combine::parser::item::one_of;
+use combine::parser::Parser;
+use combine::stream::StreamErrorFor;
+
+use crate::unicode_stream::ScanStream;
+
+/// How much the discarded parts of the input are validated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strictness {
+    /// Parse them as any other JSON value.
+    Full,
+    /// Only check that their strings are closed and their brackets balanced,
+    /// scanning them in bulk.
+    Structural,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strictness, String> {
+        match s {
+            "full" => Ok(Strictness::Full),
+            "structural" => Ok(Strictness::Structural),
+            _ => Err(format!("unknown strictness : {}", s)),
+        }
+    }
+}
+
+fn is_structural(b: u8) -> bool {
+    matches!(b, b'"' | b'\\' | b'[' | b']' | b'{' | b'}')
+}
+
+fn find_structural_portable(bytes: &[u8]) -> Option<usize> {
+    bytes.iter().position(|&b| is_structural(b))
+}
+
+/// Returns the index of the first quote, backslash or bracket of `bytes`.
+#[cfg(target_arch = "x86_64")]
+fn find_structural(bytes: &[u8]) -> Option<usize> {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 16;
+    let mut start = 0;
+
+    // SAFETY: SSE2 is always available on x86_64, and the unaligned loads stay in `bytes`.
+    unsafe {
+        let needles = [
+            _mm_set1_epi8(b'"' as i8),
+            _mm_set1_epi8(b'\\' as i8),
+            _mm_set1_epi8(b'[' as i8),
+            _mm_set1_epi8(b']' as i8),
+            _mm_set1_epi8(b'{' as i8),
+            _mm_set1_epi8(b'}' as i8),
+        ];
+
+        while start + LANES <= bytes.len() {
+            let block = _mm_loadu_si128(bytes.as_ptr().add(start) as *const __m128i);
+            let matches = needles.iter().fold(_mm_setzero_si128(), |acc, &needle| {
+                _mm_or_si128(acc, _mm_cmpeq_epi8(block, needle))
+            });
+            let mask = _mm_movemask_epi8(matches);
+
+            if mask != 0 {
+                return Some(start + mask.trailing_zeros() as usize);
+            }
+
+            start += LANES;
+        }
+    }
+
+    find_structural_portable(&bytes[start..]).map(|pos| start + pos)
+}
+
+/// Returns the index of the first quote, backslash or bracket of `bytes`.
+#[cfg(not(target_arch = "x86_64"))]
+fn find_structural(bytes: &[u8]) -> Option<usize> {
+    find_structural_portable(bytes)
+}
+
+/// Scanner of an array or an object whose opening bracket is already consumed.
+struct StructuralSkipper {
+    // Closing brackets of the containers being skipped, the innermost last
+    closers: Vec<u8>,
+    in_string: bool,
+    // The last scanned byte is a backslash in a string
+    escaped: bool,
+    error: Option<&'static str>,
+}
+
+impl StructuralSkipper {
+    fn new(opening: u8) -> StructuralSkipper {
+        StructuralSkipper {
+            closers: vec![Self::closer(opening)],
+            in_string: false,
+            escaped: false,
+            error: None,
+        }
+    }
+
+    fn closer(opening: u8) -> u8 {
+        if opening == b'[' {
+            b']'
+        } else {
+            b'}'
+        }
+    }
+
+    /// Returns the number of bytes up to the end of the skipped value,
+    /// or `None` if it doesn't end in `bytes`.
+    fn scan(&mut self, bytes: &[u8]) -> Option<usize> {
+        let mut pos = 0;
+
+        if self.escaped && !bytes.is_empty() {
+            self.escaped = false;
+            pos = 1;
+        }
+
+        while let Some(found) = find_structural(&bytes[pos..]) {
+            let b = bytes[pos + found];
+            pos += found + 1;
+
+            match b {
+                b'"' => self.in_string = !self.in_string,
+                b'\\' if self.in_string => {
+                    if pos == bytes.len() {
+                        self.escaped = true;
+                    } else {
+                        pos += 1;
+                    }
+                }
+                _ if self.in_string => {}
+                b'[' | b'{' => self.closers.push(Self::closer(b)),
+                b']' | b'}' => {
+                    if self.closers.pop() != Some(b) {
+                        self.error = Some("mismatched closing bracket");
+
+                        return Some(pos);
+                    }
+
+                    if self.closers.is_empty() {
+                        return Some(pos);
+                    }
+                }
+                _ => {
+                    self.error = Some("unexpected backslash");
+
+                    return Some(pos);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Skip an array or an object, only checking its structure.
+pub fn skip_container<I>() -> impl Parser<Input = I, Output = ()>
+where
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    one_of(b"[{".iter().cloned()).then(|opening| {
+        parser(move |input: &mut I| {
+            let mut skipper = StructuralSkipper::new(opening);
+            let done = input
+                .scan_bytes(&mut |bytes| skipper.scan(bytes))
+                .map_err(|err| {
+                    Consumed::Consumed(I::Error::from_error(input.position(), err).into())
+                })?;
+
+            let error = if !done {
+                Some(StreamErrorFor::<I>::end_of_input())
+            } else {
+                skipper
+                    .error
+                    .map(StreamErrorFor::<I>::message_static_message)
+            };
+
+            match error {
+                Some(err) => Err(Consumed::Consumed(
+                    I::Error::from_error(input.position(), err).into(),
+                )),
+                None => Ok(((), Consumed::Consumed(()))),
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skip(chunks: &[&str]) -> (Option<usize>, Option<&'static str>) {
+        let mut chunks = chunks.iter();
+        let mut skipper = StructuralSkipper::new(chunks.next().unwrap().as_bytes()[0]);
+
+        let mut result = None;
+        for chunk in chunks {
+            result = skipper.scan(chunk.as_bytes());
+            if result.is_some() {
+                break;
+            }
+        }
+
+        (result, skipper.error)
+    }
+
+    #[test]
+    fn find_structural_characters() {
+        let text = "0123456789abcdef0123456789abcdef\"";
+        assert_eq!(find_structural(text.as_bytes()), Some(32));
+        assert_eq!(find_structural(&text.as_bytes()[..32]), None);
+
+        for (i, c) in "\"\\[]{}".chars().enumerate() {
+            let text = format!("{}{}xx", "a".repeat(17 + i), c);
+            assert_eq!(find_structural(text.as_bytes()), Some(17 + i));
+        }
+    }
+
+    #[test]
+    fn skip_nested_containers() {
+        assert_eq!(
+            skip(&["{", "\"a\": [1, {\"b\": \"]}\"}], \"c\": null}, 3"]),
+            (Some(33), None)
+        );
+        assert_eq!(
+            skip(&["[", "1, [2", "], \"\\\"]\"", "] ,"]),
+            (Some(1), None)
+        );
+        assert_eq!(skip(&["[", "\"\\", "\"]\"]"]), (Some(4), None));
+    }
+
+    #[test]
+    fn detect_broken_structure() {
+        assert_eq!(
+            skip(&["[", "1, {2]"]),
+            (Some(6), Some("mismatched closing bracket"))
+        );
+        assert_eq!(
+            skip(&["[", "1, \\]"]),
+            (Some(4), Some("unexpected backslash"))
+        );
+        assert_eq!(skip(&["[", "[1, 2]"]), (None, None));
+    }
+}
@@ -6,27 +6,29 @@ use combine::parser::Parser;
 use combine::stream::Stream;
 use combine::{combine_parse_partial, combine_parser_impl, parse_mode, parser};
 
-use crate::parse_basics::{keyword_lex, number_lex, string_lex, token_lex};
+use crate::parse_basics::{keyword_lex, number_lex, opening_lex, scan_string_lex, token_lex};
+use crate::unicode_stream::ScanStream;
 
 pub fn throw_number<I>() -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    number_lex().map(|_| ())
+    // Skipped numbers are never out of range.
+    number_lex(true).map(|_| ())
 }
 
 pub fn throw_string<I>(max_length: usize) -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    string_lex(max_length).map(|_| ())
+    scan_string_lex(max_length).map(|_| ())
 }
 
 pub fn throw_keyword<I>() -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let null_val = keyword_lex("null");
@@ -38,85 +40,97 @@ where
     choice((null_val, true_val, false_val))
 }
 
-fn throw_array_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = ()>
+fn throw_array_<I>(max_text_length: usize, depth: usize) -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     between(
-        token_lex('['),
-        token_lex(']'),
-        sep_by::<(), _, _>(throw_json(max_text_length), token_lex(',')),
+        opening_lex(b'[', depth),
+        token_lex(b']'),
+        sep_by::<(), _, _>(throw_json(max_text_length, depth + 1), token_lex(b',')),
     )
 }
 
 parser! {
-    fn throw_array[I](max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    fn throw_array[I](max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
     {
-        throw_array_(*max_text_length)
+        throw_array_(*max_text_length, *depth)
     }
 }
 
-fn throw_object_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = ()>
+fn throw_object_<I>(max_text_length: usize, depth: usize) -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    let field = string_lex(max_text_length)
-        .skip(token_lex(':'))
-        .with(throw_json(max_text_length));
+    let field = scan_string_lex(max_text_length)
+        .skip(token_lex(b':'))
+        .with(throw_json(max_text_length, depth + 1));
 
     between(
-        token_lex('{'),
-        token_lex('}'),
-        sep_by::<(), _, _>(field, token_lex(',')),
+        opening_lex(b'{', depth),
+        token_lex(b'}'),
+        sep_by::<(), _, _>(field, token_lex(b',')),
     )
 }
 
 parser! {
-    fn throw_object[I](max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    fn throw_object[I](max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
     {
-        throw_object_(*max_text_length)
+        throw_object_(*max_text_length, *depth)
     }
 }
 
-fn throw_json_<I>(max_text_length: usize) -> impl Parser<Input = I, Output = ()>
+fn throw_json_<I>(max_text_length: usize, depth: usize) -> impl Parser<Input = I, Output = ()>
 where
-    I: Stream<Item = char>,
+    I: ScanStream,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     choice((
         throw_string(max_text_length),
         throw_number(),
         throw_keyword(),
-        throw_array(max_text_length),
-        throw_object(max_text_length),
+        throw_array(max_text_length, depth),
+        throw_object(max_text_length, depth),
     ))
 }
 
 parser! {
-    pub fn throw_json[I](max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    /// Skip a value nested in `depth` arrays and objects.
+    pub fn throw_json[I](max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
     {
-        throw_json_(*max_text_length)
+        throw_json_(*max_text_length, *depth)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use combine::stream::buffered::BufferedStream;
-    use combine::stream::state::State;
-    use combine::stream::IteratorStream;
+    use crate::parse_basics::MAX_DEPTH;
+    use crate::unicode_stream::ReadStream;
 
     #[test]
     fn parse_short_complex() {
         let expr = r#"{"pomme" : { "taille" :          12345,   "couleur": "jaune" },
         "random_array": [1, 2, 3    , "word" ]}"#;
 
-        let stream = BufferedStream::new(State::new(IteratorStream::new(expr.chars())), 1000);
-        assert_eq!(throw_json(expr.len()).parse(stream).unwrap().0, ());
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert_eq!(throw_json(expr.len(), 0).parse(stream).unwrap().0, ());
+    }
+    #[test]
+    fn fail_on_too_deep_nesting() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+
+        let expr = nested(MAX_DEPTH);
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert!(throw_json(10, 0).parse(stream).is_ok());
+
+        let expr = nested(MAX_DEPTH + 1);
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert!(throw_json(10, 0).parse(stream).is_err());
     }
 }
@@ -2,17 +2,19 @@ use lexical;
 use once_cell::sync::Lazy;
 use regex::Regex;
 
-use combine::error::ParseError;
-use combine::parser::byte::{alpha_num, digit, letter, spaces, bytes};
+use combine::error::{Consumed, Info, ParseError, StreamError};
+use combine::parser::byte::{alpha_num, digit, letter, spaces};
 use combine::parser::choice::optional;
 use combine::parser::combinator::recognize;
-use combine::parser::item::{any, none_of, one_of, token};
+use combine::parser::function::parser;
+use combine::parser::item::{any, none_of, one_of, token, tokens};
 use combine::parser::repeat::{count, count_min_max, skip_count_min_max};
 use combine::parser::sequence::between;
 use combine::parser::Parser;
-use combine::stream::{Stream, StreamOnce};
+use combine::stream::{Stream, StreamErrorFor, StreamOnce};
 
 use crate::json_value::NumberVal;
+use crate::unicode_stream::ScanStream;
 
 macro_rules! number_length_base_10 {
     ($n:expr) => {
@@ -38,6 +40,17 @@ pub static NUMBER_MAX_LENGTH: Lazy<usize> = Lazy::new(|| {
     *&*INTEGER_PART_MAX_LENGTH + *&*FRACTIONAL_PART_MAX_LENGTH + *&*EXPONENT_MAX_LENGTH + 2
 });
 
+/// Max length of each part of a number kept as its original digits, which are not converted.
+const EXACT_PART_MAX_LENGTH: usize = 1024;
+
+/// Max number of arrays and objects a value can be nested in : the parsers are recursive,
+/// and a deeper input would overflow the stack.
+pub const MAX_DEPTH: usize = 128;
+
+/// Stack size the parsers need to reach `MAX_DEPTH` even in debug builds, like the one
+/// of the main thread on most systems.
+pub const PARSER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
 pub fn index_expr<I>() -> impl Parser<Input = I, Output = u64>
 where
     I: Stream<Item = u8>,
@@ -45,41 +58,149 @@ where
 {
     let expr = count_min_max::<Vec<u8>, _>(1, *&*INTEGER_PART_MAX_LENGTH, digit());
 
-    expr.map(|v: Vec<u8>| {
-        let s = String::from_utf8(v).unwrap();
-        lexical::parse(&s).unwrap()
+    expr.and_then(|v: Vec<u8>| {
+        lexical::parse(&v)
+            .map_err(|_| StreamErrorFor::<I>::message_static_message("array index too big"))
     })
 }
 
-pub fn number_expr<I>() -> impl Parser<Input = I, Output = NumberVal>
+/// Parse a number, as an `i64`, an `u64` above `i64::MAX`, or as its original text for
+/// the decimals, so they are written back unchanged.
+///
+/// With `arbitrary_precision`, the numbers beyond the range or the precision of a `f64` are
+/// accepted, and the integers beyond 64 bits are kept as their original digits too.
+pub fn number_expr<I>(arbitrary_precision: bool) -> impl Parser<Input = I, Output = NumberVal>
 where
     I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
+    let (integer_max_length, fractional_max_length, exponent_max_length) = if arbitrary_precision {
+        (
+            EXACT_PART_MAX_LENGTH,
+            EXACT_PART_MAX_LENGTH,
+            EXACT_PART_MAX_LENGTH,
+        )
+    } else {
+        (
+            *INTEGER_PART_MAX_LENGTH,
+            *FRACTIONAL_PART_MAX_LENGTH,
+            *EXPONENT_MAX_LENGTH,
+        )
+    };
+
     let expr = recognize::<Vec<u8>, _>((
         optional(one_of("-+".bytes())),
-        skip_count_min_max(1, *&*INTEGER_PART_MAX_LENGTH, digit()),
+        skip_count_min_max(1, integer_max_length, digit()),
         optional((
             token(b'.'),
-            skip_count_min_max(1, *&*FRACTIONAL_PART_MAX_LENGTH, digit()),
+            skip_count_min_max(1, fractional_max_length, digit()),
         )),
         optional((
             one_of("eE".bytes()),
             optional(one_of("-+".bytes())),
-            skip_count_min_max(1, *&*EXPONENT_MAX_LENGTH, digit()),
+            skip_count_min_max(1, exponent_max_length, digit()),
         )),
     ));
 
-    expr.map(|s: Vec<u8>| {
+    expr.and_then(move |s: Vec<u8>| {
         let float_evidences = [b'.', b'e', b'E'];
-        if float_evidences.iter().any(|c| s.contains(c)) {
-            NumberVal::Float(lexical::parse(&s).unwrap())
+        let is_float = float_evidences.iter().any(|c| s.contains(c));
+
+        let number = if is_float {
+            // The value is only checked here, it is parsed again by the stages using it.
+            if arbitrary_precision || lexical::parse::<f64, _>(&s).is_ok() {
+                Some(exact_number(&s))
+            } else {
+                None
+            }
+        } else if let Ok(i) = lexical::parse(&s) {
+            Some(NumberVal::Integer(i))
+        } else if let Ok(u) = lexical::parse(&s) {
+            Some(NumberVal::Unsigned(u))
+        } else if arbitrary_precision {
+            Some(exact_number(&s))
         } else {
-            NumberVal::Integer(lexical::parse(&s).unwrap())
-        }
+            None
+        };
+
+        number.ok_or_else(|| StreamErrorFor::<I>::message_static_message("number out of range"))
     })
 }
 
+/// Number kept as its text, without the sign `+` which JSON does not allow.
+fn exact_number(s: &[u8]) -> NumberVal {
+    let digits = s.strip_prefix(b"+").unwrap_or(s);
+
+    // The number expression only recognizes ASCII characters.
+    NumberVal::Exact(String::from_utf8_lossy(digits).into_owned())
+}
+
+/// Decode the escape sequences of the raw content of a JSON string,
+/// and validate it as UTF-8.
+pub fn unescape_string(raw: Vec<u8>) -> Result<String, &'static str> {
+    fn hex_code_unit(bytes: &mut dyn Iterator<Item = u8>) -> Result<u16, &'static str> {
+        let mut unit = 0u16;
+
+        for _ in 0..4 {
+            let digit = bytes
+                .next()
+                .and_then(|b| (b as char).to_digit(16))
+                .ok_or("invalid unicode escape sequence")?;
+
+            unit = unit * 16 + digit as u16;
+        }
+
+        Ok(unit)
+    }
+
+    if !raw.contains(&b'\\') {
+        return String::from_utf8(raw).map_err(|_| "invalid UTF-8 in string");
+    }
+
+    let mut decoded = Vec::with_capacity(raw.len());
+    let mut bytes = raw.into_iter();
+
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            decoded.push(b);
+            continue;
+        }
+
+        let escaped = bytes.next().ok_or("invalid escape sequence")?;
+        let c = match escaped {
+            b'"' | b'\\' | b'/' => escaped as char,
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let unit = hex_code_unit(&mut bytes)?;
+                let units = if (0xd800..0xdc00).contains(&unit) {
+                    // High surrogate, the low one has to follow
+                    match (bytes.next(), bytes.next()) {
+                        (Some(b'\\'), Some(b'u')) => vec![unit, hex_code_unit(&mut bytes)?],
+                        _ => return Err("unpaired surrogate in unicode escape sequence"),
+                    }
+                } else {
+                    vec![unit]
+                };
+
+                std::char::decode_utf16(units)
+                    .next()
+                    .and_then(Result::ok)
+                    .ok_or("invalid unicode escape sequence")?
+            }
+            _ => return Err("invalid escape sequence"),
+        };
+
+        let mut buf = [0; 4];
+        decoded.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+
+    String::from_utf8(decoded).map_err(|_| "invalid UTF-8 in string")
+}
+
 pub fn string_expr<I>(max_length: usize) -> impl Parser<Input = I, Output = String>
 where
     I: Stream<Item = u8>,
@@ -88,11 +209,95 @@ where
     between(
         token(b'"'),
         token(b'"'),
-        count::<Vec<u8>, _>(
+        count::<Vec<[Option<u8>; 2]>, _>(
             max_length,
-            (token(b'\\').and(any()).map(|x| x.1)).or(none_of(Some(b'"').iter().cloned())),
+            token(b'\\')
+                .with(any())
+                .map(|b| [Some(b'\\'), Some(b)])
+                .or(none_of(Some(b'"').iter().cloned()).map(|b| [Some(b), None])),
         ),
-    ).map(|v: Vec<u8>| String::from_utf8(v).unwrap()) // TODO: Check special escaped characters
+    )
+    .and_then(|chars| {
+        let raw = chars.iter().flatten().flatten().cloned().collect();
+
+        unescape_string(raw).map_err(StreamErrorFor::<I>::message_static_message)
+    })
+}
+
+/// Parse a string into its raw content, the escape sequences included,
+/// taking the bytes in bulk from the buffer of the stream.
+pub fn raw_string_expr<I>(max_length: usize) -> impl Parser<Input = I, Output = Vec<u8>>
+where
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    token(b'"').with(parser(move |input: &mut I| {
+        let mut raw = Vec::new();
+        let mut escaped = false;
+
+        let done = input
+            .scan_bytes(&mut |bytes| {
+                let mut pos = 0;
+
+                if escaped && !bytes.is_empty() {
+                    escaped = false;
+                    pos = 1;
+                }
+
+                while let Some(found) = bytes[pos..].iter().position(|&b| b == b'"' || b == b'\\') {
+                    pos += found + 1;
+
+                    if bytes[pos - 1] == b'"' {
+                        raw.extend_from_slice(&bytes[..pos - 1]);
+
+                        return Some(pos);
+                    } else if pos == bytes.len() {
+                        escaped = true;
+                    } else {
+                        pos += 1;
+                    }
+                }
+
+                raw.extend_from_slice(bytes);
+
+                if raw.len() > max_length {
+                    // The remaining bytes are not needed to fail.
+                    Some(bytes.len())
+                } else {
+                    None
+                }
+            })
+            .map_err(|err| {
+                Consumed::Consumed(I::Error::from_error(input.position(), err).into())
+            })?;
+
+        let error = if !done {
+            Some(StreamErrorFor::<I>::end_of_input())
+        } else if raw.len() > max_length {
+            Some(StreamErrorFor::<I>::message_static_message(
+                "string too long",
+            ))
+        } else {
+            None
+        };
+
+        match error {
+            Some(err) => Err(Consumed::Consumed(
+                I::Error::from_error(input.position(), err).into(),
+            )),
+            None => Ok((raw, Consumed::Consumed(()))),
+        }
+    }))
+}
+
+/// Parse a string like `string_expr`, taking the bytes in bulk from the buffer of the stream.
+pub fn scan_string_expr<I>(max_length: usize) -> impl Parser<Input = I, Output = String>
+where
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    raw_string_expr(max_length)
+        .and_then(|raw| unescape_string(raw).map_err(StreamErrorFor::<I>::message_static_message))
 }
 
 pub fn regex_expr<I>(max_length: usize) -> impl Parser<Input = I, Output = Regex>
@@ -109,7 +314,22 @@ where
         ),
     );
 
-    expr.map(|s| Regex::new(&String::from_utf8(s).unwrap()).unwrap())
+    expr.and_then(|s| {
+        let s = String::from_utf8(s)
+            .map_err(|_| StreamErrorFor::<I>::message_static_message("invalid UTF-8 in regex"))?;
+
+        Regex::new(&s).map_err(|err| {
+            // The last line of the message of the regex crate tells what is wrong.
+            let message = err.to_string();
+            let reason = message
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim_start_matches("error: ");
+
+            StreamErrorFor::<I>::message_message(format!("invalid regex, {}", reason))
+        })
+    })
 }
 
 pub fn ident_expr<I>(max_length: usize) -> impl Parser<Input = I, Output = String>
@@ -123,18 +343,18 @@ where
             max_length,
             alpha_num().or(token(b'_')),
         ))
-        .map(move |(first, mut rest)| {
-            rest.insert(0, first);
-            String::from_utf8(rest).unwrap()
+        .map(move |(first, rest)| {
+            // Only made of ASCII letters, digits and underscores
+            std::iter::once(first).chain(rest).map(char::from).collect()
         })
 }
 
-pub fn keyword_expr<I>(keyword: &'static [u8]) -> impl Parser<Input = I, Output = ()>
+pub fn keyword_expr<I>(keyword: &'static str) -> impl Parser<Input = I, Output = ()>
 where
     I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    bytes(keyword).map(|_| ())
+    tokens(|l, r| l == r, Info::Borrowed(keyword), keyword.bytes()).map(|_| ())
 }
 
 pub fn lex<P>(p: P) -> impl Parser<Input = P::Input, Output = P::Output>
@@ -150,12 +370,12 @@ where
     p.skip(spaces())
 }
 
-pub fn number_lex<I>() -> impl Parser<Input = I, Output = NumberVal>
+pub fn number_lex<I>(arbitrary_precision: bool) -> impl Parser<Input = I, Output = NumberVal>
 where
     I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    lex(number_expr())
+    lex(number_expr(arbitrary_precision))
 }
 
 pub fn string_lex<I>(max_length: usize) -> impl Parser<Input = I, Output = String>
@@ -166,6 +386,14 @@ where
     lex(string_expr(max_length))
 }
 
+pub fn scan_string_lex<I>(max_length: usize) -> impl Parser<Input = I, Output = String>
+where
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    lex(scan_string_expr(max_length))
+}
+
 pub fn keyword_lex<I>(keyword: &'static str) -> impl Parser<Input = I, Output = ()>
 where
     I: Stream<Item = u8>,
@@ -182,7 +410,7 @@ where
     lex(ident_expr(max_length))
 }
 
-pub fn token_lex<I>(c: char) -> impl Parser<Input = I, Output = ()>
+pub fn token_lex<I>(c: u8) -> impl Parser<Input = I, Output = ()>
 where
     I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
@@ -190,6 +418,24 @@ where
     lex(token(c)).map(|_| ())
 }
 
+/// Parse the opening token of an array or an object nested in `depth` others,
+/// failing if it is nested too deeply.
+pub fn opening_lex<I>(c: u8, depth: usize) -> impl Parser<Input = I, Output = ()>
+where
+    I: Stream<Item = u8>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    token_lex(c).and_then(move |_| {
+        if depth < MAX_DEPTH {
+            Ok(())
+        } else {
+            Err(StreamErrorFor::<I>::message_static_message(
+                "too deeply nested",
+            ))
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -197,11 +443,13 @@ mod tests {
     use combine::stream::state::State;
     use combine::stream::IteratorStream;
 
+    use crate::unicode_stream::ReadStream;
+
     macro_rules! assert_parse_exprs {
         ($parser:expr, $exprs_and_expected:expr) => {
             for (expr, expected) in $exprs_and_expected {
                 let stream =
-                    BufferedStream::new(State::new(IteratorStream::new(expr.chars())), 1000);
+                    BufferedStream::new(State::new(IteratorStream::new(expr.bytes())), 1000);
 
                 assert_eq!($parser.parse(stream).unwrap().0, expected);
             }
@@ -224,6 +472,40 @@ mod tests {
         assert_parse_exprs!(string_expr(1000), exprs_and_expected);
     }
 
+    #[test]
+    fn parse_escaped_string() {
+        let exprs_and_expected = vec![
+            (
+                r#""quote \" and \\""#.to_string(),
+                "quote \" and \\".to_string(),
+            ),
+            (
+                r#""tab\tnew\nline""#.to_string(),
+                "tab\tnew\nline".to_string(),
+            ),
+            (r#""\u00e9\ud83d\ude00""#.to_string(), "é😀".to_string()),
+            ("\"é😀\"".to_string(), "é😀".to_string()),
+        ];
+
+        assert_parse_exprs!(string_expr(1000), exprs_and_expected);
+    }
+
+    #[test]
+    fn scan_escaped_string_across_chunks() {
+        // The backslash ends the first 64 KiB chunk read by the stream.
+        let content = "a".repeat(64 * 1024 - 2);
+        let expr = format!("\"{}\\\"b\\u00e9\" ", content);
+
+        let stream = ReadStream::from_read(expr.as_bytes());
+        assert_eq!(
+            scan_string_expr(100_000).parse(stream).unwrap().0,
+            format!("{}\"bé", content)
+        );
+
+        let stream = ReadStream::from_read(&b"\"too long\""[..]);
+        assert!(scan_string_expr(5).parse(stream).is_err());
+    }
+
     #[test]
     fn parse_integer() {
         let expected = vec![0i64, 1i64, 9i64, 10i64, 123456789i64, -1i64, -1345601i64];
@@ -233,7 +515,7 @@ mod tests {
             .map(|e| (e.to_string(), NumberVal::Integer(e)))
             .collect();
 
-        assert_parse_exprs!(number_expr(), exprs_and_expected);
+        assert_parse_exprs!(number_expr(false), exprs_and_expected);
     }
 
     #[test]
@@ -251,14 +533,62 @@ mod tests {
 
         let exprs_and_expected: Vec<(String, _)> = expected
             .into_iter()
-            .map(|e| (e.to_string(), NumberVal::Float(e)))
+            .map(|e| (e.to_string(), NumberVal::Exact(e.to_string())))
             .collect();
 
-        assert_parse_exprs!(number_expr(), exprs_and_expected);
+        assert_parse_exprs!(number_expr(false), exprs_and_expected);
+    }
+
+    #[test]
+    fn parse_float_with_exponent() {
+        let exprs_and_expected = vec![("1e3", 1000f64), ("-2.5E-3", -0.0025f64), ("1.10", 1.1f64)];
+
+        for (expr, expected) in exprs_and_expected {
+            let stream = BufferedStream::new(State::new(IteratorStream::new(expr.bytes())), 1000);
+            let number = number_expr(false).parse(stream).unwrap().0;
+
+            assert_eq!(number, NumberVal::Exact(expr.to_string()));
+            assert_eq!(number.as_f64(), expected);
+        }
     }
 
     #[test]
-    fn parse_float_with_exponent() {}
+    fn parse_big_number() {
+        let exprs_and_expected = vec![
+            (
+                "18446744073709551615",
+                NumberVal::Unsigned(u64::max_value()),
+            ),
+            ("+9223372036854775808", NumberVal::Unsigned(1 << 63)),
+            ("-9223372036854775808", NumberVal::Integer(i64::min_value())),
+        ];
+
+        assert_parse_exprs!(number_expr(false), exprs_and_expected);
+
+        let stream = BufferedStream::new(
+            State::new(IteratorStream::new("18446744073709551616".bytes())),
+            1000,
+        );
+        assert!(number_expr(false).parse(stream).is_err());
+    }
+
+    #[test]
+    fn parse_exact_number() {
+        let exprs_and_expected = vec![
+            ("12", NumberVal::Integer(12)),
+            (
+                "18446744073709551616",
+                NumberVal::Exact("18446744073709551616".to_string()),
+            ),
+            (
+                "+0.10000000000000000001",
+                NumberVal::Exact("0.10000000000000000001".to_string()),
+            ),
+            ("-1E400", NumberVal::Exact("-1E400".to_string())),
+        ];
+
+        assert_parse_exprs!(number_expr(true), exprs_and_expected);
+    }
 
     #[test]
     fn parse_ident() {
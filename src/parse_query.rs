@@ -1,7 +1,7 @@
 use std::ops::Range;
 
-use combine::char::alpha_num;
 use combine::error::ParseError;
+use combine::parser::byte::alpha_num;
 use combine::parser::choice::{choice, optional};
 use combine::parser::combinator::{attempt, not_followed_by};
 use combine::parser::item::{eof, token};
@@ -24,43 +24,39 @@ use crate::pipeline_builder::StageArg;
 
 fn path_parser<I>(max_text_length: usize) -> impl Parser<Input = I, Output = JsonPath>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    let field_path_expr = token('.')
+    let field_path_expr = token(b'.')
         .with(string_expr(max_text_length).or(ident_expr(max_text_length)))
-        .message("field_path_expr")
         .map(|field_name| JsonPathStep::Field(field_name));
 
-    let index_path_expr = between(token('['), token(']'), index_expr())
-        .message("index_path_expr")
+    let index_path_expr = between(token(b'['), token(b']'), index_expr())
         .map(|array_index| JsonPathStep::Index(array_index));
 
     let path_step_expr = field_path_expr.or(index_path_expr);
 
     choice((
-        attempt(token('.').skip(not_followed_by(alpha_num()))).map(|_| JsonPath::root()),
+        attempt(token(b'.').skip(not_followed_by(alpha_num()))).map(|_| JsonPath::root()),
         attempt(many1::<Vec<_>, _>(path_step_expr)).map(|v| JsonPath::new(v)),
     ))
-    .message("path_parser")
 }
 
 fn filter_parser<I>(max_text_length: usize) -> impl Parser<Input = I, Output = Filter>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let array_filter_expr_internal = index_expr()
         .and(optional(choice((
-            many::<Vec<_>, _>(token(',').with(index_expr())).map(|v| ArrayFilter::OneOf(v)),
-            token(':').with(index_expr()).map(|right_bound| {
+            many::<Vec<_>, _>(token(b',').with(index_expr())).map(|v| ArrayFilter::OneOf(v)),
+            token(b':').with(index_expr()).map(|right_bound| {
                 ArrayFilter::Range(Range {
                     start: 0,
                     end: right_bound,
                 })
             }),
         ))))
-        .message("array_filter_expr_internal")
         .map(|(num, maybe_rest)| {
             if let Some(array_filter) = maybe_rest {
                 match array_filter {
@@ -81,27 +77,22 @@ where
             }
         });
 
-    let array_filter_expr = between(token('['), token(']'), array_filter_expr_internal)
-        .message("array_filter_expr")
+    let array_filter_expr = between(token(b'['), token(b']'), array_filter_expr_internal)
         .map(|array_filter| FilterPart::Array(array_filter));
 
-    let branch_filter_expr = token('.')
-        .with(choice((
-            string_expr(max_text_length)
-                .map(|branch_name| FilterPart::Branch(BranchFilter::TextMatch(branch_name))),
-            ident_expr(max_text_length)
-                .message("ident_expr")
-                .map(|branch_name| FilterPart::Branch(BranchFilter::TextMatch(branch_name))),
-            regex_expr(max_text_length)
-                .map(|reg| FilterPart::Branch(BranchFilter::RegexMatch(reg))),
-        )))
-        .message("branch_filter_expr");
+    let branch_filter_expr = token(b'.').with(choice((
+        string_expr(max_text_length)
+            .map(|branch_name| FilterPart::Branch(BranchFilter::TextMatch(branch_name))),
+        ident_expr(max_text_length)
+            .map(|branch_name| FilterPart::Branch(BranchFilter::TextMatch(branch_name))),
+        regex_expr(max_text_length).map(|reg| FilterPart::Branch(BranchFilter::RegexMatch(reg))),
+    )));
 
     let filter_part_expr = array_filter_expr.or(branch_filter_expr);
 
     let filter_expr = attempt(
-        token('.')
-            .skip(not_followed_by(alpha_num().or(token('"')).or(token('/'))))
+        token(b'.')
+            .skip(not_followed_by(alpha_num().or(token(b'"')).or(token(b'/'))))
             .map(|_| vec![]),
     )
     .or(many::<Vec<_>, _>(filter_part_expr))
@@ -113,62 +104,160 @@ where
         }
     });
 
-    sep_by1::<Vec<_>, _, _>(filter_expr, token_lex(','))
-        .map(|mut v| {
-            if v.len() == 1 {
-                v.pop().unwrap() // We can unwrap because v.len() == 1
-            } else {
-                Filter::Union(v)
-            }
-        })
-        .message("filter_parser")
+    sep_by1::<Vec<_>, _, _>(filter_expr, token_lex(b',')).map(Filter::union)
 }
 
 fn stage_parser<I>(
     max_text_length: usize,
 ) -> impl Parser<Input = I, Output = (String, Vec<StageArg>)>
 where
-    I: Stream<Item = char>,
+    I: Stream<Item = u8>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     ident_lex(max_text_length).and(many::<Vec<StageArg>, _>(choice((
-        number_lex().map(|n: NumberVal| StageArg::Number(n)),
+        number_lex(false).map(|n: NumberVal| StageArg::Number(n)),
         string_lex(max_text_length).map(|s: String| StageArg::String(s)),
         lex(path_parser(max_text_length)).map(|p: JsonPath| StageArg::Path(p)),
     ))))
 }
 
-pub fn parse_query<'a>(
+pub fn parse_path(max_text_length: usize, path: &str) -> Result<JsonPath, InitError> {
+    path_parser(max_text_length)
+        .skip(eof())
+        .easy_parse(State::new(path.as_bytes()))
+        .map(|(path, _)| path)
+        .map_err(|err| InitError::path_syntax(path, err))
+}
+
+/// Builds a stage from its arguments, ending with the given pipeline.
+type StageBuilder =
+    fn(Box<dyn Pipeline>, &[StageArg], OpenBranch) -> Result<Box<dyn Pipeline>, InitError>;
+
+/// A stage which can be used in the queries.
+struct StageKind {
+    name: &'static str,
+    /// Builds the stage from its arguments, ending with the given pipeline.
+    build: StageBuilder,
+    /// Processes each item on its own, without any state or output of its own.
+    per_item: bool,
+    /// Reduces all the items into one, from states which can be merged.
+    aggregating: bool,
+}
+
+/// The stages built by `build_stages`.
+const STAGE_KINDS: [StageKind; 5] = [
+    StageKind {
+        name: "add_field",
+        build: |output, args, _| AddFieldStage::from_args(output, args),
+        per_item: true,
+        aggregating: false,
+    },
+    StageKind {
+        name: "mean",
+        build: |output, args, _| MeanStage::from_args(output, args),
+        per_item: false,
+        aggregating: true,
+    },
+    StageKind {
+        name: "select",
+        build: |output, args, _| SelectStage::from_args(output, args),
+        per_item: true,
+        aggregating: false,
+    },
+    StageKind {
+        name: "sum",
+        build: |output, args, _| SumStage::from_args(output, args),
+        per_item: false,
+        aggregating: true,
+    },
+    StageKind {
+        name: "tee",
+        build: TeeStage::from_args,
+        per_item: false,
+        aggregating: false,
+    },
+];
+
+fn stage_kind(stage_ident: &str) -> Option<&'static StageKind> {
+    STAGE_KINDS.iter().find(|kind| kind.name == stage_ident)
+}
+
+/// A stage of a query, with its name and arguments.
+pub type StageSpec = (String, Vec<StageArg>);
+
+/// Whether the stage processes each item on its own, without any state or output of its own.
+pub fn is_per_item_stage(stage_ident: &str) -> bool {
+    matches!(stage_kind(stage_ident), Some(kind) if kind.per_item)
+}
+
+/// Whether the stage reduces all the items into one, from states which can be merged.
+pub fn is_aggregating_stage(stage_ident: &str) -> bool {
+    matches!(stage_kind(stage_ident), Some(kind) if kind.aggregating)
+}
+
+/// Parses the query into its filter and its stages.
+pub fn parse_query_stages(
     max_text_length: usize,
-    output: Box<dyn Pipeline>,
     query: &str,
-) -> Result<(Filter, Box<dyn Pipeline + 'a>), InitError> {
+) -> Result<(Filter, Vec<StageSpec>), InitError> {
     let mut parser = lex(filter_parser(max_text_length))
         .and(many::<Vec<_>, _>(
-            token_lex('|').with(stage_parser(max_text_length)),
+            token_lex(b'|').with(stage_parser(max_text_length)),
         ))
         .skip(eof());
 
-    let (filter, stages) = parser
-        .easy_parse(State::new(query))
+    parser
+        .easy_parse(State::new(query.as_bytes()))
         .map(|(filter, _)| filter)
-        .map_err(|err| InitError::WrongQuerySyntax {
-            position: err.position.column,
-        })?;
+        .map_err(|err| InitError::query_syntax(query, err))
+}
 
+/// Builds the pipeline made of `stages`, ending with `output`.
+///
+/// `open_branch` opens the additional outputs of the stages writing to a file, like `tee`.
+/// With `dead_letters`, the items failing a stage are sent to it instead of failing the pipeline.
+pub fn build_stages<'a>(
+    output: Box<dyn Pipeline>,
+    stages: &[StageSpec],
+    open_branch: OpenBranch,
+    dead_letters: Option<&DeadLetters>,
+) -> Result<Box<dyn Pipeline + 'a>, InitError> {
     let mut pipeline = output;
 
     for (stage_ident, args) in stages.iter().rev() {
-        pipeline = match stage_ident.as_str() {
-            "add_field" => AddFieldStage::from_args(pipeline, &args),
-            "mean" => MeanStage::from_args(pipeline, &args),
-            "sum" => SumStage::from_args(pipeline, &args),
-            "select" => SelectStage::from_args(pipeline, &args),
-            &_ => Err(InitError::StageUnknownName {
-                unknown_name: stage_ident.to_string(),
-            }),
-        }?;
+        let kind = stage_kind(stage_ident).ok_or_else(|| {
+            let names: Vec<_> = STAGE_KINDS.iter().map(|kind| kind.name).collect();
+
+            InitError::stage_unknown_name(stage_ident, &names)
+        })?;
+        pipeline = (kind.build)(pipeline, args, open_branch)?;
+
+        if let Some(dead_letters) = dead_letters {
+            pipeline = Box::new(DeadLetterStage::new(
+                stage_ident,
+                pipeline,
+                dead_letters.clone(),
+            ));
+        }
     }
 
-    Ok((filter, pipeline))
+    Ok(pipeline)
+}
+
+/// Parses the query and builds its pipeline, ending with `output`.
+///
+/// `open_branch` and `dead_letters` are used like by `build_stages`.
+pub fn parse_query<'a>(
+    max_text_length: usize,
+    output: Box<dyn Pipeline>,
+    query: &str,
+    open_branch: OpenBranch,
+    dead_letters: Option<&DeadLetters>,
+) -> Result<(Filter, Box<dyn Pipeline + 'a>), InitError> {
+    let (filter, stages) = parse_query_stages(max_text_length, query)?;
+
+    Ok((
+        filter,
+        build_stages(output, &stages, open_branch, dead_letters)?,
+    ))
 }
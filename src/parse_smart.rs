@@ -1,38 +1,276 @@
 use either::Either;
+use indexmap::IndexMap;
 use std::cell::RefCell;
+use std::io::{self, Write};
 use std::rc::Rc;
 
+use combine::error::{ParseError, StreamError};
+use combine::parser::byte::spaces;
 use combine::parser::choice::choice;
 use combine::parser::combinator::factory;
-use combine::parser::repeat::sep_by;
+use combine::parser::item::eof;
+use combine::parser::repeat::{sep_by, skip_many};
 use combine::parser::sequence::between;
-use combine::stream::Stream;
+use combine::parser::Parser;
+use combine::stream::StreamErrorFor;
 use combine::{combine_parse_partial, combine_parser_impl, parse_mode, parser};
 
 use crate::error::PipelineError;
 use crate::filter::Filter;
-use crate::json_path::JsonPath;
-use crate::json_value::JsonValue;
-use crate::parse_and_keep::keep_json;
+use crate::json_format::{write_json, write_json_string, FormatOptions};
+use crate::json_path::{JsonPath, JsonPathStep};
+use crate::json_value::{JsonValue, NumberVal};
+use crate::parse_and_keep::{keep_json, keep_scalar};
+use crate::parse_and_skip::{skip_container, Strictness};
 use crate::parse_and_throw::{throw_json, throw_keyword, throw_number, throw_string};
-use crate::parse_basics::{lex, string_lex, token_lex};
-use crate::pipeline::Pipeline;
+use crate::parse_basics::{
+    lex, opening_lex, raw_string_expr, scan_string_lex, token_lex, unescape_string,
+};
+use crate::pipeline::{PartialAggregate, Pipeline, RawOutput, SharedItemPath};
+use crate::unicode_stream::ScanStream;
 
-struct InternalState {
+/// What is ingested for each kept value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathOutput {
+    /// The value itself.
+    Value,
+    /// An object `{"path": ..., "value": ...}` wrapping the value with its path.
+    ValueWithPath,
+    /// Only the path of the value.
+    PathOnly,
+}
+
+impl PathOutput {
+    fn wrap(self, value: JsonValue, pos: &JsonPath) -> JsonValue {
+        match self {
+            PathOutput::Value => value,
+            PathOutput::ValueWithPath => JsonValue::Object(
+                vec![
+                    ("path".to_string(), JsonValue::String(pos.to_string())),
+                    ("value".to_string(), value),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            PathOutput::PathOnly => JsonValue::String(pos.to_string()),
+        }
+    }
+}
+
+/// Collection keeping only its last item, to know the last element of a container
+/// without storing all of them.
+#[derive(Default)]
+struct LastItem<T>(Option<T>);
+
+impl<T> Extend<T> for LastItem<T> {
+    fn extend<It: IntoIterator<Item = T>>(&mut self, iter: It) {
+        if let Some(last) = iter.into_iter().last() {
+            self.0 = Some(last);
+        }
+    }
+}
+
+/// Text of a kept value being copied, written to the output only once the value is complete.
+///
+/// Like a kept value, it is held whole in memory, with the names of the fields of its open
+/// objects : the duplicated ones can only be rewritten once their last value is known.
+#[derive(Default)]
+struct CopiedText {
+    text: Vec<u8>,
+    // Objects being copied, from the outermost one
+    objects: Vec<CopiedObject>,
+}
+
+/// Object being copied, whose fields are rewritten when it closes if some are duplicated,
+/// to keep only their last value at their first place like a kept object.
+struct CopiedObject {
+    start: usize,
+    // Range of the text of each field, and the field being copied with its start
+    fields: IndexMap<String, (usize, usize)>,
+    field: Option<(String, usize)>,
+    duplicated: bool,
+}
+
+impl CopiedText {
+    fn open_object(&mut self) {
+        self.objects.push(CopiedObject {
+            start: self.text.len(),
+            fields: IndexMap::new(),
+            field: None,
+            duplicated: false,
+        });
+        self.text.push(b'{');
+    }
+
+    fn open_field(&mut self, name: String, options: FormatOptions) {
+        let start = self.text.len();
+        // Writing to a `Vec` can't fail.
+        let _ = write_json_string(&mut self.text, &name, options)
+            .and_then(|_| self.text.write_all(b":"));
+        if let Some(object) = self.objects.last_mut() {
+            object.field = Some((name, start));
+        }
+    }
+
+    fn close_field(&mut self) {
+        let end = self.text.len();
+        if let Some(object) = self.objects.last_mut() {
+            if let Some((name, start)) = object.field.take() {
+                object.duplicated |= object.fields.insert(name, (start, end)).is_some();
+            }
+        }
+    }
+
+    fn close_object(&mut self) {
+        let object = match self.objects.pop() {
+            Some(object) => object,
+            None => return,
+        };
+
+        if object.duplicated {
+            let mut rewritten = vec![b'{'];
+            for (i, &(start, end)) in object.fields.values().enumerate() {
+                if i > 0 {
+                    rewritten.push(b',');
+                }
+                rewritten.extend_from_slice(&self.text[start..end]);
+            }
+            self.text.truncate(object.start);
+            self.text.append(&mut rewritten);
+        }
+        self.text.push(b'}');
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+        self.objects.clear();
+    }
+}
+
+/// A query of the parser : the values matching its filter are ingested by its pipeline.
+struct Route {
+    filter: Filter,
     pipeline: RefCell<Box<dyn Pipeline>>,
+}
+
+impl Route {
+    /// Ingest the parts of a value kept at `pos` which are matching the filter of the route.
+    /// `pos` is restored before returning.
+    fn ingest_matching(
+        &self,
+        value: &JsonValue,
+        pos: &mut JsonPath,
+        path_output: PathOutput,
+        item_path: Option<&SharedItemPath>,
+    ) -> Result<(), PipelineError> {
+        if self.filter.is_match(pos) {
+            if let Some(item_path) = item_path {
+                *item_path.borrow_mut() = pos.clone();
+            }
+
+            return self
+                .pipeline
+                .borrow_mut()
+                .ingest(path_output.wrap(value.clone(), pos));
+        }
+
+        if !self.filter.is_subpath(pos) {
+            return Ok(());
+        }
+
+        match value {
+            JsonValue::Object(ref fields) => {
+                for (field_name, field) in fields {
+                    pos.push_field(field_name);
+                    let result = self.ingest_matching(field, pos, path_output, item_path);
+                    pos.pop_field();
+                    result?;
+                }
+            }
+            JsonValue::Array(ref elems) => {
+                pos.push_index(0);
+                for elem in elems {
+                    let result = self.ingest_matching(elem, pos, path_output, item_path);
+                    if result.is_err() {
+                        pos.pop_index();
+                        return result;
+                    }
+                    pos.inc_index();
+                }
+                pos.pop_index();
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Options of the parsing of the input.
+#[derive(Debug, Clone, Copy)]
+pub struct ParserOptions {
+    pub path_output: PathOutput,
+    /// Kept values are ingested as [path, leaf] events instead of whole values.
+    pub stream: bool,
+    /// Validation of the parts of the input which are not kept.
+    pub skip_strictness: Strictness,
+    /// Numbers beyond the range or the precision of 64 bits are kept as their original digits.
+    pub arbitrary_precision: bool,
+    /// Input not conforming to RFC 8259 is rejected.
+    pub strict: bool,
+}
+
+struct InternalState {
+    routes: Vec<Route>,
+    // Union of the filters of the routes
     filter: Filter,
     pos: RefCell<JsonPath>,
+    options: ParserOptions,
+    // Kept values are copied as text to the output instead of being built, with these options
+    copy: Option<FormatOptions>,
+    copied: RefCell<CopiedText>,
+    // Error of a pipeline which stopped the parsing
+    error: RefCell<Option<PipelineError>>,
+    // Set to the path of each ingested value, for the dead letters
+    item_path: Option<SharedItemPath>,
 }
 
 #[derive(Clone)]
 pub struct ParserState(Rc<InternalState>);
 
 impl ParserState {
-    pub fn new(pipeline: Box<dyn Pipeline>, filter: Filter) -> ParserState {
+    /// Create a state parsing the input once for all the queries.
+    /// If given, `item_path` is set to the path of each value ingested.
+    pub fn new(
+        routes: Vec<(Filter, Box<dyn Pipeline>)>,
+        options: ParserOptions,
+        item_path: Option<SharedItemPath>,
+    ) -> ParserState {
+        let filter = Filter::union(routes.iter().map(|(filter, _)| filter.clone()).collect());
+        let mut routes: Vec<Route> = routes
+            .into_iter()
+            .map(|(filter, pipeline)| Route {
+                filter,
+                pipeline: RefCell::new(pipeline),
+            })
+            .collect();
+        // The kept values can be copied only if they are written as they are, by the only query.
+        let copy = match routes.as_mut_slice() {
+            [route] if options.path_output == PathOutput::Value && !options.stream => {
+                route.pipeline.get_mut().raw_output().map(|raw| raw.options)
+            }
+            _ => None,
+        };
+
         ParserState(Rc::new(InternalState {
-            pipeline: RefCell::new(pipeline),
+            routes,
             filter,
             pos: RefCell::new(JsonPath::root()),
+            options,
+            copy,
+            copied: RefCell::new(CopiedText::default()),
+            error: RefCell::new(None),
+            item_path,
         }))
     }
 
@@ -64,40 +302,206 @@ impl ParserState {
         self.0.filter.is_subpath(&self.0.pos.borrow())
     }
 
+    fn is_streamed(&self) -> bool {
+        self.0.options.stream
+    }
+
+    fn is_arbitrary_precision(&self) -> bool {
+        self.0.options.arbitrary_precision
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.0.options.strict
+    }
+
+    fn is_skipped_in_bulk(&self) -> bool {
+        self.0.options.skip_strictness == Strictness::Structural
+    }
+
+    fn is_copied(&self) -> bool {
+        self.0.copy.is_some()
+    }
+
+    /// Add a part of a copied value to its text.
+    fn copy_raw<F>(&self, write: F)
+    where
+        F: FnOnce(RawOutput) -> io::Result<()>,
+    {
+        let mut copied = self.0.copied.borrow_mut();
+        // Writing to a `Vec` can't fail.
+        let _ = write(RawOutput {
+            writer: &mut copied.text,
+            options: self.copy_options(),
+        });
+    }
+
+    fn copy_options(&self) -> FormatOptions {
+        self.0.copy.unwrap_or_default()
+    }
+
+    fn with_copied<F: FnOnce(&mut CopiedText)>(&self, update: F) {
+        update(&mut self.0.copied.borrow_mut())
+    }
+
+    /// Write the text of a complete copied value to the raw output of the only query.
+    fn flush_copied(&self) -> Result<(), PipelineError> {
+        let mut copied = self.0.copied.borrow_mut();
+        let mut pipeline = self.0.routes[0].pipeline.borrow_mut();
+        let raw_output = pipeline
+            .raw_output()
+            .ok_or(PipelineError::UnableToWriteOuptut)?;
+        let result = raw_output
+            .writer
+            .write_all(&copied.text)
+            .and_then(|_| writeln!(raw_output.writer))
+            .map_err(|_| PipelineError::UnableToWriteOuptut);
+        copied.clear();
+
+        result
+    }
+
+    fn depth(&self) -> usize {
+        self.0.pos.borrow().len()
+    }
+
+    /// Ingest a `[path]` event closing the container of the current value if `leaf` is `None`,
+    /// else a `[path, leaf]` event, into the queries whose kept value holds them.
+    /// The path is relative to the value kept by each query.
+    fn ingest_event(&self, leaf: Option<JsonValue>) -> Result<(), PipelineError> {
+        let pos = self.0.pos.borrow();
+        self.set_item_path(&pos);
+
+        for route in &self.0.routes {
+            let depth = match route.filter.match_depth(&pos) {
+                // The closed container must be inside the kept value, not be one of its parents.
+                Some(depth) if leaf.is_some() || depth < pos.len() => depth,
+                _ => continue,
+            };
+            let path = pos
+                .iter()
+                .skip(depth)
+                .map(|step| match step {
+                    JsonPathStep::Field(name) => JsonValue::String(name.clone()),
+                    JsonPathStep::Index(index) => {
+                        JsonValue::Number(NumberVal::Integer(*index as i64))
+                    }
+                })
+                .collect();
+            let event = JsonValue::Array(
+                std::iter::once(JsonValue::Array(path))
+                    .chain(leaf.clone())
+                    .collect(),
+            );
+
+            route.pipeline.borrow_mut().ingest(event)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_item_path(&self, pos: &JsonPath) {
+        if let Some(ref item_path) = self.0.item_path {
+            *item_path.borrow_mut() = pos.clone();
+        }
+    }
+
+    /// Route a kept value to the pipelines of the queries matching it.
     fn ingest(&self, item: JsonValue) -> Result<(), PipelineError> {
-        self.0.pipeline.borrow_mut().ingest(item)
+        if let [route] = self.0.routes.as_slice() {
+            // Nothing to route, and the value can be moved instead of cloned.
+            let item = self.0.options.path_output.wrap(item, &self.0.pos.borrow());
+            self.set_item_path(&self.0.pos.borrow());
+
+            return route.pipeline.borrow_mut().ingest(item);
+        }
+
+        let mut pos = self.0.pos.borrow().clone();
+
+        for route in &self.0.routes {
+            route.ingest_matching(
+                &item,
+                &mut pos,
+                self.0.options.path_output,
+                self.0.item_path.as_ref(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop the parsing if a pipeline failed, keeping its error to report it instead of
+    /// the parse error this causes.
+    pub fn check<E>(&self, result: Result<(), PipelineError>) -> Result<(), E>
+    where
+        E: StreamError<u8, u8>,
+    {
+        result.map_err(|err| {
+            *self.0.error.borrow_mut() = Some(err);
+
+            E::message_static_message("pipeline error")
+        })
+    }
+
+    /// Take the error of the pipeline which stopped the parsing, if any.
+    pub fn take_error(&self) -> Option<PipelineError> {
+        self.0.error.borrow_mut().take()
+    }
+
+    /// Path of the value being parsed.
+    pub fn path(&self) -> JsonPath {
+        self.0.pos.borrow().clone()
+    }
+
+    /// Go back to the root, after a parsing failure left the path of the failing value.
+    pub fn reset_path(&self) {
+        *self.0.pos.borrow_mut() = JsonPath::root();
+        self.0.copied.borrow_mut().clear();
+    }
+
+    /// Take the states of the aggregating stages of the queries, see `Pipeline::take_partial`.
+    pub fn take_partials(&self) -> Vec<Option<PartialAggregate>> {
+        self.0
+            .routes
+            .iter()
+            .map(|route| route.pipeline.borrow_mut().take_partial())
+            .collect()
     }
 
     pub fn finish(&self) -> Result<(), PipelineError> {
-        self.0.pipeline.borrow_mut().finish()
+        for route in &self.0.routes {
+            route.pipeline.borrow_mut().finish()?;
+        }
+
+        Ok(())
     }
 }
 
 parser! {
     fn array_smart[I](state: ParserState, max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    where [I: ScanStream]
     {
         let state_clone1 = state.clone();
         let state_clone2 = state.clone();
         let state_clone3 = state.clone();
 
         between(
-            token_lex('[').map(move |_| { state_clone1.enter_array(); }),
-            token_lex(']').map(move |_| { state_clone2.exit_array(); }),
-            sep_by::<(), _, _>(json_smart(state.clone(), *max_text_length), token_lex(',').map(move |_| { state_clone3.next_elem(); }))
+            opening_lex(b'[', state.depth()).map(move |_| { state_clone1.enter_array(); }),
+            token_lex(b']').map(move |_| { state_clone2.exit_array(); }),
+            sep_by::<(), _, _>(json_smart(state.clone(), *max_text_length), token_lex(b',').map(move |_| { state_clone3.next_elem(); }))
         )
     }
 }
 
 parser! {
     fn object_smart[I](state: ParserState, max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    where [I: ScanStream]
     {
+        let depth = state.depth();
         let field = {
             let state_clone1 = state.clone();
             let state_clone2 = state.clone();
 
-            string_lex(*max_text_length).skip(token_lex(':')).then(move |field_name| {
+            scan_string_lex(*max_text_length).skip(token_lex(b':')).then(move |field_name| {
                 state_clone1.enter_node(&field_name);
 
                 json_smart(state.clone(), *max_text_length)
@@ -105,39 +509,447 @@ parser! {
         };
 
         between(
-            token_lex('{'),
-            token_lex('}'),
-            sep_by::<(), _, _>(field, token_lex(','))
+            opening_lex(b'{', depth),
+            token_lex(b'}'),
+            sep_by::<(), _, _>(field, token_lex(b','))
         )
     }
 }
 
 parser! {
     fn keep_json_smart[I](state: ParserState, max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    where [I: ScanStream]
     {
-        lex(keep_json(*max_text_length).map(move |v| { state.ingest(v).unwrap(); }))
+        lex(keep_json(*max_text_length, state.is_arbitrary_precision(), state.depth()).and_then(move |v| state.check::<StreamErrorFor<I>>(state.ingest(v))))
+    }
+}
+
+parser! {
+    fn copy_array[I](state: ParserState, max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone1 = state.clone();
+        let state_clone2 = state.clone();
+        let state_clone3 = state.clone();
+
+        between(
+            opening_lex(b'[', *depth).map(move |_| state_clone1.copy_raw(|raw| raw.writer.write_all(b"["))),
+            token_lex(b']').map(move |_| state_clone2.copy_raw(|raw| raw.writer.write_all(b"]"))),
+            sep_by::<(), _, _>(copy_json(state.clone(), *max_text_length, *depth + 1), token_lex(b',').map(move |_| state_clone3.copy_raw(|raw| raw.writer.write_all(b","))))
+        )
+    }
+}
+
+parser! {
+    fn copy_object[I](state: ParserState, max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone1 = state.clone();
+        let state_clone2 = state.clone();
+        let state_clone3 = state.clone();
+        let state_clone4 = state.clone();
+        let state_clone5 = state.clone();
+
+        let field = scan_string_lex(*max_text_length).skip(token_lex(b':')).map(move |field_name| {
+            let options = state_clone1.copy_options();
+            state_clone1.with_copied(|copied| copied.open_field(field_name, options));
+        }).with(copy_json(state.clone(), *max_text_length, *depth + 1)).map(move |_| state_clone5.with_copied(CopiedText::close_field));
+
+        between(
+            opening_lex(b'{', *depth).map(move |_| state_clone2.with_copied(CopiedText::open_object)),
+            token_lex(b'}').map(move |_| state_clone3.with_copied(CopiedText::close_object)),
+            sep_by::<(), _, _>(field, token_lex(b',').map(move |_| state_clone4.copy_raw(|raw| raw.writer.write_all(b","))))
+        )
+    }
+}
+
+parser! {
+    /// Copy a value nested in `depth` arrays and objects.
+    fn copy_json[I](state: ParserState, max_text_length: usize, depth: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone = state.clone();
+
+        let state_clone2 = state.clone();
+
+        choice((
+            lex(raw_string_expr(*max_text_length)).and_then(|raw| {
+                // Strings which would be written unchanged are copied as they are.
+                if raw.iter().all(|&b| (0x20..0x7f).contains(&b) && b != b'\\') {
+                    Ok(Either::Left(raw))
+                } else {
+                    unescape_string(raw)
+                        .map(|s| Either::Right(JsonValue::normalized_string(s)))
+                        .map_err(StreamErrorFor::<I>::message_static_message)
+                }
+            }).map(move |string| {
+                state_clone2.copy_raw(|raw| match string {
+                    Either::Left(ref bytes) => {
+                        raw.writer.write_all(b"\"")?;
+                        raw.writer.write_all(bytes)?;
+                        raw.writer.write_all(b"\"")
+                    }
+                    Either::Right(ref v) => write_json(raw.writer, v, None, raw.options),
+                })
+            }),
+            lex(keep_scalar(*max_text_length, state.is_arbitrary_precision())).map(move |v| {
+                state_clone.copy_raw(|raw| write_json(raw.writer, &v, None, raw.options))
+            }),
+            copy_array(state.clone(), *max_text_length, *depth),
+            copy_object(state.clone(), *max_text_length, *depth),
+        ))
+    }
+}
+
+parser! {
+    fn copy_json_smart[I](state: ParserState, max_text_length: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone = state.clone();
+
+        copy_json(state.clone(), *max_text_length, state.depth()).and_then(move |_| {
+            state_clone.check::<StreamErrorFor<I>>(state_clone.flush_copied())
+        })
+    }
+}
+
+parser! {
+    fn stream_array[I](state: ParserState, max_text_length: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone1 = state.clone();
+        let state_clone2 = state.clone();
+        let state_clone3 = state.clone();
+
+        between(
+            opening_lex(b'[', state.depth()).map(move |_| { state_clone1.enter_array(); }),
+            token_lex(b']'),
+            sep_by::<LastItem<()>, _, _>(stream_json(state.clone(), *max_text_length), token_lex(b',').map(move |_| { state_clone3.next_elem(); }))
+        ).and_then(move |last_elem| {
+            let result = if last_elem.0.is_some() {
+                // The closing event holds the path of the last element
+                let result = state_clone2.ingest_event(None);
+                state_clone2.exit_array();
+                result
+            } else {
+                state_clone2.exit_array();
+                state_clone2.ingest_event(Some(JsonValue::Array(Vec::new())))
+            };
+
+            state_clone2.check::<StreamErrorFor<I>>(result)
+        })
+    }
+}
+
+parser! {
+    fn stream_object[I](state: ParserState, max_text_length: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let field = {
+            let state_clone1 = state.clone();
+            let state_clone2 = state.clone();
+            let state_clone3 = state.clone();
+            let max_text_length = *max_text_length;
+
+            scan_string_lex(max_text_length).skip(token_lex(b':')).then(move |field_name| {
+                state_clone1.enter_node(&field_name);
+
+                stream_json(state_clone2.clone(), max_text_length).map(move |_| field_name.clone())
+            }).map(move |field_name| {
+                state_clone3.exit_node();
+
+                field_name
+            })
+        };
+        let state_clone = state.clone();
+
+        between(
+            opening_lex(b'{', state.depth()),
+            token_lex(b'}'),
+            sep_by::<LastItem<String>, _, _>(field, token_lex(b','))
+        ).and_then(move |last_field| {
+            let result = if let Some(field_name) = last_field.0 {
+                // The closing event holds the path of the last field
+                state_clone.enter_node(&field_name);
+                let result = state_clone.ingest_event(None);
+                state_clone.exit_node();
+                result
+            } else {
+                state_clone.ingest_event(Some(JsonValue::Object(Default::default())))
+            };
+
+            state_clone.check::<StreamErrorFor<I>>(result)
+        })
+    }
+}
+
+parser! {
+    fn stream_json[I](state: ParserState, max_text_length: usize)(I) -> ()
+    where [I: ScanStream]
+    {
+        let state_clone = state.clone();
+
+        choice((
+            lex(keep_scalar(*max_text_length, state.is_arbitrary_precision())).and_then(move |v| state_clone.check::<StreamErrorFor<I>>(state_clone.ingest_event(Some(v)))),
+            stream_array(state.clone(), *max_text_length),
+            stream_object(state.clone(), *max_text_length),
+        ))
     }
 }
 
 parser! {
     pub fn json_smart[I](state: ParserState, max_text_length: usize)(I) -> ()
-    where [I: Stream<Item = char>]
+    where [I: ScanStream]
     {
         factory(move ||
-            if state.is_keeped() {
-                Either::Left(keep_json_smart(state.clone(), *max_text_length))
+            if state.is_keeped() && state.is_streamed() {
+                Either::Left(Either::Left(stream_json(state.clone(), *max_text_length)))
+            } else if state.is_keeped() && state.is_copied() {
+                Either::Left(Either::Right(copy_json_smart(state.clone(), *max_text_length).left()))
+            } else if state.is_keeped() {
+                Either::Left(Either::Right(keep_json_smart(state.clone(), *max_text_length).right()))
             } else if state.is_containing_keeped() {
-                Either::Right(choice((
+                Either::Right(Either::Left(choice((
                     throw_string(*max_text_length),
                     throw_number(),
                     throw_keyword(),
                     array_smart(state.clone(), *max_text_length),
                     object_smart(state.clone(), *max_text_length),
-                )).left())
+                ))))
+            } else if state.is_skipped_in_bulk() {
+                Either::Right(Either::Right(choice((
+                    throw_string(*max_text_length),
+                    throw_number(),
+                    throw_keyword(),
+                    lex(skip_container()),
+                )).left()))
             } else {
-                Either::Right(throw_json(*max_text_length).right())
+                Either::Right(Either::Right(throw_json(*max_text_length, state.depth()).right()))
             }
         )
     }
 }
+
+/// Parser of a whole input made of any number of JSON values.
+pub fn json_stream_smart<I>(
+    state: ParserState,
+    max_text_length: usize,
+) -> impl Parser<Input = I, Output = ()>
+where
+    I: ScanStream,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    spaces()
+        .with(skip_many(json_smart(state, max_text_length)))
+        .skip(eof())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use crate::error::InitError;
+    use crate::parse_basics::PARSER_STACK_SIZE;
+    use crate::parse_query::{build_stages, parse_path, parse_query_stages};
+    use crate::pipeline::WriteStage;
+    use crate::unicode_stream::{ReadStream, SliceStream};
+
+    // Corpus of malformed queries and inputs, mutated from seeds, checking that the parsers
+    // report errors instead of panicking.
+
+    const MAX_TEXT_LENGTH: usize = 64;
+    const MUTATIONS_PER_SEED: usize = 40;
+
+    const QUERY_SEEDS: &[&str] = &[
+        ".",
+        ".a",
+        ".a.b[0]",
+        ".\"a b\"[1,2,3]",
+        ".a[0:2]",
+        "./a(b|c)+/",
+        ".a, .b[1]",
+        ". | sum .a",
+        ". | mean .a.b",
+        ". | select .a | sum .",
+        ". | add_field \"k\" \"v\"",
+        ". | tee \"file\"",
+        ".a[99999999999999999999999]",
+        "./(/",
+        "./\\/é/",
+    ];
+
+    const INPUT_SEEDS: &[&[u8]] = &[
+        b"",
+        b"{}",
+        b"[]",
+        b"{\"a\": 1, \"b\": [1, 2.5, -3e10, true, false, null]}",
+        b"{\"a\": {\"b\": [{\"c\": \"d\"}]}} {\"a\": 2}",
+        b"[1, [2, [3, [4, [5]]]]]",
+        b"\"\\u00e9\\ud83d\\ude00\\n\\\"\"",
+        b"\"\\ud83d\" \"\\udc00\" \"\\u12\"",
+        b"99999999999999999999 -99999999999999999999 1e999 1e400 -0.0",
+        b"{\"a\": \"x\"} {\"a\": [1, 2]} 3",
+        b"\xff\xfe {\"\xc3\": \"\xe9\"}",
+        b"{\"a\": 1}\n{\"a\": tru}\n[1, 2,\n",
+        b"{\"a\" 1} {\"a\": 1,} [1 2] [,]",
+    ];
+
+    /// Values nested far deeper than `MAX_DEPTH`, which must not overflow the stack.
+    fn deep_nesting_seeds() -> Vec<Vec<u8>> {
+        let depth = 10_000;
+
+        let mut arrays = b"[".repeat(depth);
+        arrays.extend(b"]".repeat(depth));
+        let mut objects = b"{\"a\": ".repeat(depth);
+        objects.push(b'1');
+        objects.extend(b"}".repeat(depth));
+
+        vec![arrays, objects]
+    }
+
+    /// Bytes which are the most likely to change the meaning of a query or a JSON input.
+    const INTERESTING_BYTES: &[u8] = b"{}[]\",:\\/.|0123456789eE+-tfnu \n\x00\xc3\xff";
+
+    /// Pseudo-random generator, to mutate the seeds the same way on every run.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound.max(1) as u64) as usize
+        }
+    }
+
+    /// Returns `seed` with a few random insertions, deletions, duplications or truncations.
+    fn mutate(seed: &[u8], rng: &mut XorShift) -> Vec<u8> {
+        let mut bytes = seed.to_vec();
+
+        for _ in 0..=rng.below(3) {
+            let pos = rng.below(bytes.len() + 1);
+
+            match rng.below(4) {
+                0 => bytes.insert(pos, INTERESTING_BYTES[rng.below(INTERESTING_BYTES.len())]),
+                1 if pos < bytes.len() => {
+                    bytes.remove(pos);
+                }
+                2 => {
+                    let end = pos + rng.below(bytes.len() - pos + 1);
+                    let copy = bytes[pos..end].to_vec();
+                    bytes.splice(pos..pos, copy);
+                }
+                _ => bytes.truncate(pos),
+            }
+        }
+
+        bytes
+    }
+
+    fn corpus<S: AsRef<[u8]>>(seeds: &[S], seed: u64) -> Vec<Vec<u8>> {
+        let mut rng = XorShift(seed);
+        let mut corpus = Vec::new();
+
+        for seed in seeds {
+            corpus.push(seed.as_ref().to_vec());
+
+            for _ in 0..MUTATIONS_PER_SEED {
+                corpus.push(mutate(seed.as_ref(), &mut rng));
+            }
+        }
+
+        corpus
+    }
+
+    fn build_query(query: &str) -> Result<(Filter, Box<dyn Pipeline>), InitError> {
+        let (filter, stages) = parse_query_stages(MAX_TEXT_LENGTH, query)?;
+        let output = Box::new(WriteStage::with_options(
+            Vec::new(),
+            FormatOptions::default(),
+        ));
+        let pipeline = build_stages(
+            output,
+            &stages,
+            &|filename| {
+                Err(InitError::UnableToOpenFile {
+                    filename: filename.to_string(),
+                })
+            },
+            None,
+        )?;
+
+        Ok((filter, pipeline))
+    }
+
+    #[test]
+    fn malformed_queries_do_not_panic() {
+        let seeds: Vec<_> = QUERY_SEEDS.iter().map(|seed| seed.as_bytes()).collect();
+
+        for query in corpus(&seeds, 0x5eed_0001) {
+            let query = String::from_utf8_lossy(&query);
+
+            let _ = build_query(&query);
+            let _ = parse_path(MAX_TEXT_LENGTH, &query);
+        }
+    }
+
+    fn parse_input_corpus() {
+        let queries = [".", ".a[1]", ". | sum .a", ".a, .b"];
+        let option_sets = [
+            (PathOutput::Value, false, Strictness::Full, false),
+            (
+                PathOutput::ValueWithPath,
+                false,
+                Strictness::Structural,
+                true,
+            ),
+            (PathOutput::PathOnly, false, Strictness::Full, false),
+            (PathOutput::Value, true, Strictness::Structural, true),
+        ];
+
+        let mut seeds: Vec<Vec<u8>> = INPUT_SEEDS.iter().map(|seed| seed.to_vec()).collect();
+        seeds.extend(deep_nesting_seeds());
+
+        for input in corpus(&seeds, 0x5eed_0002) {
+            for query in queries.iter() {
+                for &(path_output, stream, skip_strictness, arbitrary_precision) in
+                    option_sets.iter()
+                {
+                    let options = ParserOptions {
+                        path_output,
+                        stream,
+                        skip_strictness,
+                        arbitrary_precision,
+                        strict: false,
+                    };
+
+                    let state = ParserState::new(vec![build_query(query).unwrap()], options, None);
+                    let _ = json_stream_smart(state.clone(), MAX_TEXT_LENGTH)
+                        .parse(SliceStream::new(&input));
+                    let _ = state.finish();
+
+                    let state = ParserState::new(vec![build_query(query).unwrap()], options, None);
+                    let _ = json_stream_smart(state.clone(), MAX_TEXT_LENGTH)
+                        .parse(ReadStream::from_read(&input[..]));
+                    let _ = state.finish();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_inputs_do_not_panic() {
+        // The deepest values accepted need a larger stack than the one of the tests.
+        thread::Builder::new()
+            .stack_size(PARSER_STACK_SIZE)
+            .spawn(parse_input_corpus)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
@@ -1,9 +1,17 @@
-use std::cell::Cell;
+use indexmap::IndexMap;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::io::Write;
+use std::rc::Rc;
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlEmitter};
 
+use crate::colored_json::write_colored;
 use crate::error::{InitError, PipelineError};
+use crate::json_format::{write_json, FormatOptions};
 use crate::json_path::JsonPath;
 use crate::json_value::{JsonValue, NumberVal};
+use crate::output::{commit_temporary_file, rotated_filename, OutputWrite};
 use crate::pipeline_builder::StageArg;
 
 pub trait Pipeline {
@@ -16,48 +24,487 @@ pub trait Pipeline {
     /// It has to call the finish method of its output(s).
     /// The main use of this method is for aggregating stages.
     fn finish(&mut self) -> Result<(), PipelineError>;
+
+    /// Fail like `ingest` would on an item this stage can't process, without ingesting it.
+    /// The following stages are not checked.
+    fn check_item(&self, _item: &JsonValue) -> Result<(), PipelineError> {
+        Ok(())
+    }
+
+    /// Writer into which the text of the items can be copied instead of ingesting them,
+    /// if the pipeline would only write them as compact JSON.
+    fn raw_output(&mut self) -> Option<RawOutput<'_>> {
+        None
+    }
+
+    /// Take the state of the first aggregating stage of the pipeline, which is then like if
+    /// it ingested nothing. Only the stages processing each item on its own can precede it.
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        None
+    }
+
+    /// Merge the state taken from another instance of this aggregating stage,
+    /// which ingested other items. The other stages ignore it.
+    fn merge_partial(&mut self, _partial: PartialAggregate) {}
+}
+
+/// State of an aggregating stage, so parts of the stream can be aggregated separately.
+#[derive(Debug, Clone, Copy)]
+pub enum PartialAggregate {
+    Sum(Option<SumAcc>),
+    Mean { acc: f64, count: u64 },
+}
+
+/// Accumulator of a sum, exact while only integers are summed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SumAcc {
+    Integer(i128),
+    Float(f64),
+    /// An integer did not fit in 128 bits.
+    Overflow,
+}
+
+impl SumAcc {
+    fn of(number: &NumberVal) -> SumAcc {
+        if !number.is_integer() {
+            SumAcc::Float(number.as_f64())
+        } else if let Some(i) = number.as_i128() {
+            SumAcc::Integer(i)
+        } else {
+            SumAcc::Overflow
+        }
+    }
+
+    fn add(self, other: SumAcc) -> SumAcc {
+        match (self, other) {
+            (SumAcc::Overflow, _) | (_, SumAcc::Overflow) => SumAcc::Overflow,
+            (SumAcc::Integer(a), SumAcc::Integer(b)) => {
+                a.checked_add(b).map_or(SumAcc::Overflow, SumAcc::Integer)
+            }
+            (SumAcc::Float(a), SumAcc::Float(b)) => SumAcc::Float(a + b),
+            (SumAcc::Integer(a), SumAcc::Float(b)) | (SumAcc::Float(b), SumAcc::Integer(a)) => {
+                SumAcc::Float(a as f64 + b)
+            }
+        }
+    }
+}
+
+/// Output of the items copied as text by the parser, formatted like `write_json` would.
+pub struct RawOutput<'a> {
+    pub writer: &'a mut dyn Write,
+    pub options: FormatOptions,
+}
+
+pub struct WriteStage<W: OutputWrite> {
+    output: W,
+    options: FormatOptions,
+}
+
+impl<W: OutputWrite> WriteStage<W> {
+    pub fn with_options(output: W, options: FormatOptions) -> WriteStage<W> {
+        WriteStage { output, options }
+    }
+}
+
+impl<W: OutputWrite> Pipeline for WriteStage<W> {
+    fn ingest(&mut self, mut item: JsonValue) -> Result<(), PipelineError> {
+        if self.options.sort_keys {
+            item.sort_keys();
+        }
+
+        write_json(&mut self.output, &item, None, self.options)
+            .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        writeln!(&mut self.output).map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        self.output
+            .finish_output()
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+
+    fn raw_output(&mut self) -> Option<RawOutput<'_>> {
+        // Sorting the keys requires the whole objects.
+        if self.options.sort_keys {
+            None
+        } else {
+            Some(RawOutput {
+                writer: &mut self.output,
+                options: self.options,
+            })
+        }
+    }
+}
+
+pub struct WritePrettyStage<W: OutputWrite> {
+    output: W,
+    indent: Vec<u8>,
+    options: FormatOptions,
+}
+
+impl<W: OutputWrite> WritePrettyStage<W> {
+    pub fn with_options(output: W, indent: Vec<u8>, options: FormatOptions) -> WritePrettyStage<W> {
+        WritePrettyStage {
+            output,
+            indent,
+            options,
+        }
+    }
+}
+
+impl<W: OutputWrite> Pipeline for WritePrettyStage<W> {
+    fn ingest(&mut self, mut item: JsonValue) -> Result<(), PipelineError> {
+        if self.options.sort_keys {
+            item.sort_keys();
+        }
+
+        write_json(&mut self.output, &item, Some(&self.indent), self.options)
+            .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        writeln!(&mut self.output).map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        self.output
+            .finish_output()
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+}
+
+pub struct WriteColoredStage<W: OutputWrite> {
+    output: W,
+    indent: Option<Vec<u8>>,
+    options: FormatOptions,
+}
+
+impl<W: OutputWrite> WriteColoredStage<W> {
+    pub fn new(output: W, indent: Option<Vec<u8>>, options: FormatOptions) -> WriteColoredStage<W> {
+        WriteColoredStage {
+            output,
+            indent,
+            options,
+        }
+    }
+}
+
+impl<W: OutputWrite> Pipeline for WriteColoredStage<W> {
+    fn ingest(&mut self, mut item: JsonValue) -> Result<(), PipelineError> {
+        if self.options.sort_keys {
+            item.sort_keys();
+        }
+
+        write_colored(
+            &mut self.output,
+            &item,
+            self.indent.as_deref(),
+            self.options,
+        )
+        .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        writeln!(&mut self.output).map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        self.output
+            .finish_output()
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
 }
 
-pub struct WriteStage<W: Write>(W);
+pub struct WriteYamlStage<W: OutputWrite>(W);
 
-impl<W: Write> WriteStage<W> {
-    pub fn new(output: W) -> WriteStage<W> {
-        WriteStage(output)
+/// YAML document of a value, whose numbers are written as they are in JSON : the exact
+/// numbers keep their original text, which is also a valid YAML number.
+fn yaml_document(value: &JsonValue) -> Yaml {
+    match value {
+        JsonValue::Null => Yaml::Null,
+        JsonValue::String(s) => Yaml::String(s.clone()),
+        JsonValue::Number(NumberVal::Integer(i)) => Yaml::Integer(*i),
+        JsonValue::Number(NumberVal::Unsigned(u)) => Yaml::Real(u.to_string()),
+        JsonValue::Number(NumberVal::Float(f)) if f.is_finite() => {
+            Yaml::Real(ryu::Buffer::new().format_finite(*f).to_string())
+        }
+        JsonValue::Number(NumberVal::Float(f)) if f.is_nan() => Yaml::Real(".nan".to_string()),
+        JsonValue::Number(NumberVal::Float(f)) if *f > 0.0 => Yaml::Real(".inf".to_string()),
+        JsonValue::Number(NumberVal::Float(_)) => Yaml::Real("-.inf".to_string()),
+        JsonValue::Number(NumberVal::Exact(digits)) => Yaml::Real(digits.clone()),
+        JsonValue::Boolean(b) => Yaml::Boolean(*b),
+        JsonValue::Array(elems) => Yaml::Array(elems.iter().map(yaml_document).collect()),
+        JsonValue::Object(fields) => {
+            let mut hash = Hash::new();
+            for (key, value) in fields {
+                hash.insert(Yaml::String(key.clone()), yaml_document(value));
+            }
+            Yaml::Hash(hash)
+        }
+    }
+}
+
+impl<W: OutputWrite> WriteYamlStage<W> {
+    pub fn new(output: W) -> WriteYamlStage<W> {
+        WriteYamlStage(output)
     }
 }
 
-impl<W: Write> Pipeline for WriteStage<W> {
+impl<W: OutputWrite> Pipeline for WriteYamlStage<W> {
     fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
-        serde_json::to_writer(&mut self.0, &item)
+        // Each item is written as a document, starting with its own `---` separator.
+        let mut document = String::new();
+        YamlEmitter::new(&mut document)
+            .dump(&yaml_document(&item))
             .map_err(|_| PipelineError::UnableToWriteOuptut)?;
-        writeln!(&mut self.0).map_err(|_| PipelineError::UnableToWriteOuptut)
+        writeln!(self.0, "{}", document).map_err(|_| PipelineError::UnableToWriteOuptut)
     }
 
     fn finish(&mut self) -> Result<(), PipelineError> {
+        self.0
+            .finish_output()
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+}
+
+/// Placeholder of the partition name in the filename template of a `PartitionStage`.
+pub const PARTITION_PLACEHOLDER: &str = "{}";
+
+/// Opens the output of a partition, given its filename and whether it was already opened before.
+pub type PartitionOpener = Box<dyn FnMut(&str, bool) -> Result<Box<dyn Pipeline>, PipelineError>>;
+
+/// Dispatches the items into one output per value found at `key`.
+///
+/// At most `max_open_files` outputs are open at the same time,
+/// the least recently used one is finished and closed to open a new one.
+pub struct PartitionStage {
+    key: JsonPath,
+    filename_template: String,
+    max_open_files: usize,
+    // Ordered from the least to the most recently used.
+    open_outputs: IndexMap<String, Box<dyn Pipeline>>,
+    opened_filenames: HashSet<String>,
+    open_output: PartitionOpener,
+}
+
+impl PartitionStage {
+    pub fn new(
+        key: JsonPath,
+        filename_template: String,
+        max_open_files: usize,
+        open_output: PartitionOpener,
+    ) -> PartitionStage {
+        PartitionStage {
+            key,
+            filename_template,
+            max_open_files,
+            open_outputs: IndexMap::new(),
+            opened_filenames: HashSet::new(),
+            open_output,
+        }
+    }
+
+    /// Returns the name of the partition of an item, usable in a filename.
+    /// Items missing the key belong to the `null` partition.
+    fn partition_name(&self, item: &JsonValue) -> String {
+        let name = match item.select(&self.key) {
+            Some(JsonValue::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => JsonValue::Null.to_string(),
+        };
+
+        // Names like `..` would point to another directory than the one of the template.
+        if name.chars().all(|c| c == '.') {
+            return "_".repeat(name.len().max(1));
+        }
+
+        name.replace(['/', '\\', '\0'], "_")
+    }
+
+    fn take_output(&mut self, filename: &str) -> Result<Box<dyn Pipeline>, PipelineError> {
+        if let Some(output) = self.open_outputs.shift_remove(filename) {
+            return Ok(output);
+        }
+
+        if self.open_outputs.len() >= self.max_open_files {
+            if let Some((_, mut least_recently_used)) = self.open_outputs.shift_remove_index(0) {
+                least_recently_used.finish()?;
+            }
+        }
+
+        let reopen = !self.opened_filenames.insert(filename.to_string());
+
+        (self.open_output)(filename, reopen)
+    }
+}
+
+impl Pipeline for PartitionStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        let filename = self
+            .filename_template
+            .replace(PARTITION_PLACEHOLDER, &self.partition_name(&item));
+
+        let mut output = self.take_output(&filename)?;
+        let result = output.ingest(item);
+        self.open_outputs.insert(filename, output);
+
+        result
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        for (_, mut output) in self.open_outputs.drain(..) {
+            output.finish()?;
+        }
+
+        self.opened_filenames.clear();
+
         Ok(())
     }
 }
 
-pub struct WritePrettyStage<W: Write>(W);
+/// Opens an output file, given its filename and the counter of the bytes written into it.
+pub type RotationOpener =
+    Box<dyn FnMut(&str, Rc<Cell<u64>>) -> Result<Box<dyn Pipeline>, PipelineError>>;
 
-impl<W: Write> WritePrettyStage<W> {
-    pub fn new(output: W) -> WritePrettyStage<W> {
-        WritePrettyStage(output)
+/// Writes the items into a sequence of files, moving on to the next file
+/// when the current one reaches `max_bytes` or `max_items`.
+pub struct RotateStage {
+    filename: String,
+    max_bytes: Option<u64>,
+    max_items: Option<u64>,
+    index: u64,
+    items: u64,
+    written_bytes: Rc<Cell<u64>>,
+    output: Option<Box<dyn Pipeline>>,
+    open_output: RotationOpener,
+}
+
+impl RotateStage {
+    /// Creates the stage and opens its first file, so an unwritable output is detected early.
+    pub fn new(
+        filename: String,
+        max_bytes: Option<u64>,
+        max_items: Option<u64>,
+        open_output: RotationOpener,
+    ) -> Result<RotateStage, PipelineError> {
+        let mut stage = RotateStage {
+            filename,
+            max_bytes,
+            max_items,
+            index: 0,
+            items: 0,
+            written_bytes: Rc::new(Cell::new(0)),
+            output: None,
+            open_output,
+        };
+
+        stage.rotate()?;
+
+        Ok(stage)
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_items.is_some_and(|max| self.items >= max)
+            || self
+                .max_bytes
+                .is_some_and(|max| self.written_bytes.get() >= max)
+    }
+
+    /// Finishes the current file, if any, and opens the next one.
+    fn rotate(&mut self) -> Result<(), PipelineError> {
+        if let Some(mut output) = self.output.take() {
+            output.finish()?;
+        }
+
+        self.index += 1;
+        self.items = 0;
+        self.written_bytes.set(0);
+
+        let filename = rotated_filename(&self.filename, self.index);
+        self.output = Some((self.open_output)(&filename, self.written_bytes.clone())?);
+
+        Ok(())
     }
 }
 
-impl<W: Write> Pipeline for WritePrettyStage<W> {
+impl Pipeline for RotateStage {
     fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
-        serde_json::to_writer_pretty(&mut self.0, &item)
-            .map_err(|_| PipelineError::UnableToWriteOuptut)?;
-        writeln!(&mut self.0).map_err(|_| PipelineError::UnableToWriteOuptut)
+        if self.output.is_none() || self.is_full() {
+            self.rotate()?;
+        }
+
+        self.items += 1;
+
+        match self.output {
+            Some(ref mut output) => output.ingest(item),
+            None => unreachable!(), // The rotation always leaves an open output
+        }
     }
 
     fn finish(&mut self) -> Result<(), PipelineError> {
+        if let Some(mut output) = self.output.take() {
+            output.finish()?;
+        }
+
+        self.index = 0;
+        self.items = 0;
+        self.written_bytes.set(0);
+
         Ok(())
     }
 }
 
+/// Writes into a temporary file, renamed over the target file only once the output is finished.
+/// The temporary file is removed if the stage is dropped before, so a failed run leaves
+/// the target file untouched.
+pub struct AtomicOutputStage {
+    output: Option<Box<dyn Pipeline>>,
+    temporary_filename: String,
+    filename: String,
+    committed: bool,
+}
+
+impl AtomicOutputStage {
+    pub fn new(
+        output: Box<dyn Pipeline>,
+        temporary_filename: String,
+        filename: String,
+    ) -> AtomicOutputStage {
+        AtomicOutputStage {
+            output: Some(output),
+            temporary_filename,
+            filename,
+            committed: false,
+        }
+    }
+}
+
+impl Pipeline for AtomicOutputStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        match self.output {
+            Some(ref mut output) => output.ingest(item),
+            None => Err(PipelineError::UnableToWriteOuptut),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        if let Some(mut output) = self.output.take() {
+            output.finish()?;
+            // The output has to be closed before the rename.
+            drop(output);
+
+            commit_temporary_file(&self.temporary_filename, &self.filename)
+                .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+            self.committed = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for AtomicOutputStage {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.output.take();
+            let _ = std::fs::remove_file(&self.temporary_filename);
+        }
+    }
+}
+
 pub struct AddFieldStage {
     key: String,
     value: JsonValue,
@@ -91,7 +538,7 @@ impl AddFieldStage {
                     Ok(Box::new(Self::new(
                         output,
                         &key,
-                        JsonValue::normalized_string(&value),
+                        JsonValue::normalized_string(value.clone()),
                     )))
                 } else {
                     Err(InitError::StageWrongArgType {
@@ -120,13 +567,122 @@ impl Pipeline for AddFieldStage {
         }
     }
 
+    fn check_item(&self, item: &JsonValue) -> Result<(), PipelineError> {
+        if let JsonValue::Object(_) = item {
+            Ok(())
+        } else {
+            Err(PipelineError::NotAnObject {
+                value: item.clone(),
+            })
+        }
+    }
+
     fn finish(&mut self) -> Result<(), PipelineError> {
         self.output.finish()
     }
+
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        self.output.take_partial()
+    }
+}
+
+/// Opens the additional output of a stage writing to a file, like `tee`.
+pub type OpenBranch<'a> = &'a dyn Fn(&str) -> Result<Box<dyn Pipeline>, InitError>;
+
+/// Sends the items to a side output, like a file, as well as to the next stage.
+pub struct TeeStage {
+    branch: Box<dyn Pipeline>,
+    output: Box<dyn Pipeline>,
+}
+
+impl TeeStage {
+    pub fn new(output: Box<dyn Pipeline>, branch: Box<dyn Pipeline>) -> TeeStage {
+        TeeStage { branch, output }
+    }
+
+    pub fn from_args(
+        output: Box<dyn Pipeline>,
+        args: &[StageArg],
+        open_branch: OpenBranch,
+    ) -> Result<Box<dyn Pipeline>, InitError> {
+        if args.len() != 1 {
+            Err(InitError::StageWrongNumberArgs {
+                stage_name: "tee".to_string(),
+                expected: 1,
+                got: args.len(),
+            })
+        } else {
+            if let StageArg::String(ref filename) = args.first().unwrap() {
+                // We can unwrap because args.len() == 1
+                Ok(Box::new(Self::new(output, open_branch(filename)?)))
+            } else {
+                Err(InitError::StageWrongArgType {
+                    stage_name: "tee".to_string(),
+                    arg_pos: 1,
+                })
+            }
+        }
+    }
+}
+
+impl Pipeline for TeeStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        self.branch.ingest(item.clone())?;
+        self.output.ingest(item)
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        self.branch.finish()?;
+        self.output.finish()
+    }
+}
+
+struct SharedOutput {
+    output: RefCell<Box<dyn Pipeline>>,
+    // Number of stages sharing the output which are not finished yet
+    unfinished: Cell<usize>,
+}
+
+/// Sends the items of several pipelines to the same output, which is finished
+/// once all of them are finished.
+pub struct SharedOutputStage(Rc<SharedOutput>);
+
+impl SharedOutputStage {
+    /// Returns `count` stages sharing `output`.
+    pub fn split(output: Box<dyn Pipeline>, count: usize) -> Vec<Box<dyn Pipeline>> {
+        let shared = Rc::new(SharedOutput {
+            output: RefCell::new(output),
+            unfinished: Cell::new(count),
+        });
+
+        (0..count)
+            .map(|_| Box::new(SharedOutputStage(shared.clone())) as Box<dyn Pipeline>)
+            .collect()
+    }
+}
+
+impl Pipeline for SharedOutputStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        self.0.output.borrow_mut().ingest(item)
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        match self.0.unfinished.get() {
+            0 => Ok(()),
+            1 => {
+                self.0.unfinished.set(0);
+                self.0.output.borrow_mut().finish()
+            }
+            unfinished => {
+                self.0.unfinished.set(unfinished - 1);
+                Ok(())
+            }
+        }
+    }
 }
 
 pub struct SumStage {
-    acc: Cell<Option<NumberVal>>,
+    acc: Cell<Option<SumAcc>>,
     summed_value: JsonPath,
     strict: bool,
     output: Box<dyn Pipeline>,
@@ -164,53 +720,73 @@ impl SumStage {
             }
         }
     }
+
+    fn add(&self, item_val: SumAcc) {
+        self.acc.set(Some(match self.acc.get() {
+            None => item_val,
+            Some(acc) => acc.add(item_val),
+        }));
+    }
+}
+
+/// Number at `path` in the item, to be summed or averaged. In `strict` mode, a missing
+/// value is an error, else the item is ignored.
+fn summed_number<'a>(
+    item: &'a JsonValue,
+    path: &JsonPath,
+    strict: bool,
+) -> Result<Option<&'a NumberVal>, PipelineError> {
+    match item.select(path) {
+        Some(JsonValue::Number(number)) => Ok(Some(number)),
+        Some(value) => Err(PipelineError::NotANumber {
+            value: value.clone(),
+            path: path.clone(),
+        }),
+        None if strict => Err(PipelineError::MissingValue { path: path.clone() }),
+        None => Ok(None),
+    }
 }
 
 impl Pipeline for SumStage {
     fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
-        if let Some(item) = item.select(&self.summed_value) {
-            if let JsonValue::Number(item_val) = item {
-                match (self.acc.get(), item_val) {
-                    (None, item_val) => self.acc.set(Some(*item_val)),
-                    (Some(NumberVal::Integer(acc)), &NumberVal::Integer(i)) => {
-                        self.acc.set(Some(NumberVal::Integer(acc + i)))
-                    }
-                    (Some(NumberVal::Float(acc)), &NumberVal::Float(f)) => {
-                        self.acc.set(Some(NumberVal::Float(acc + f)))
-                    }
-                    (Some(NumberVal::Integer(acc)), &NumberVal::Float(f)) => {
-                        self.acc.set(Some(NumberVal::Float((acc as f64) + f)))
-                    }
-                    (Some(NumberVal::Float(acc)), &NumberVal::Integer(i)) => {
-                        self.acc.set(Some(NumberVal::Float(acc + (i as f64))))
-                    }
-                }
-            } else {
-                return Err(PipelineError::NotANumber {
-                    value: item.clone(),
-                    path: self.summed_value.clone(),
-                });
-            }
-
-            Ok(())
-        } else if self.strict {
-            Err(PipelineError::MissingValue {
-                path: self.summed_value.clone(),
-            })
-        } else {
-            Ok(())
+        if let Some(item_val) = summed_number(&item, &self.summed_value, self.strict)? {
+            self.add(SumAcc::of(item_val));
         }
+
+        Ok(())
+    }
+
+    fn check_item(&self, item: &JsonValue) -> Result<(), PipelineError> {
+        summed_number(item, &self.summed_value, self.strict).map(|_| ())
     }
 
     fn finish(&mut self) -> Result<(), PipelineError> {
-        self.output.ingest(JsonValue::Number(
-            self.acc.get().unwrap_or(NumberVal::Integer(0)),
-        ))?;
+        let sum = match self.acc.replace(None) {
+            None => NumberVal::Integer(0),
+            Some(SumAcc::Integer(i)) => NumberVal::from_i128(i),
+            Some(SumAcc::Float(f)) => NumberVal::Float(f),
+            Some(SumAcc::Overflow) => {
+                return Err(PipelineError::SumOverflow {
+                    path: self.summed_value.clone(),
+                })
+            }
+        };
+
+        self.output.ingest(JsonValue::Number(sum))?;
         self.output.finish()?;
-        self.acc.replace(None);
 
         Ok(())
     }
+
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        Some(PartialAggregate::Sum(self.acc.replace(None)))
+    }
+
+    fn merge_partial(&mut self, partial: PartialAggregate) {
+        if let PartialAggregate::Sum(Some(acc)) = partial {
+            self.add(acc);
+        }
+    }
 }
 
 pub struct MeanStage {
@@ -261,29 +837,16 @@ impl MeanStage {
 
 impl Pipeline for MeanStage {
     fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
-        if let Some(item) = item.select(&self.meaned_value) {
-            if let JsonValue::Number(item_val) = item {
-                match item_val {
-                    &NumberVal::Integer(i) => self.acc.set(self.acc.get() + i as f64),
-                    &NumberVal::Float(i) => self.acc.set(self.acc.get() + i),
-                }
-            } else {
-                return Err(PipelineError::NotANumber {
-                    value: item.clone(),
-                    path: self.meaned_value.clone(),
-                });
-            }
-
+        if let Some(item_val) = summed_number(&item, &self.meaned_value, self.strict)? {
+            self.acc.set(self.acc.get() + item_val.as_f64());
             self.count.set(self.count.get() + 1);
-
-            Ok(())
-        } else if self.strict {
-            Err(PipelineError::MissingValue {
-                path: self.meaned_value.clone(),
-            })
-        } else {
-            Ok(())
         }
+
+        Ok(())
+    }
+
+    fn check_item(&self, item: &JsonValue) -> Result<(), PipelineError> {
+        summed_number(item, &self.meaned_value, self.strict).map(|_| ())
     }
 
     fn finish(&mut self) -> Result<(), PipelineError> {
@@ -300,6 +863,20 @@ impl Pipeline for MeanStage {
 
         Ok(())
     }
+
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        Some(PartialAggregate::Mean {
+            acc: self.acc.replace(0.0f64),
+            count: self.count.replace(0u64),
+        })
+    }
+
+    fn merge_partial(&mut self, partial: PartialAggregate) {
+        if let PartialAggregate::Mean { acc, count } = partial {
+            self.acc.set(self.acc.get() + acc);
+            self.count.set(self.count.get() + count);
+        }
+    }
 }
 
 pub struct SelectStage {
@@ -353,4 +930,108 @@ impl Pipeline for SelectStage {
 
         Ok(())
     }
+
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        self.output.take_partial()
+    }
+}
+
+/// An item which failed a stage of a query, set aside instead of stopping the processing.
+pub struct DeadLetter {
+    pub stage: String,
+    pub error: PipelineError,
+    /// Path of the item in the input.
+    pub path: JsonPath,
+    pub item: JsonValue,
+}
+
+/// Destination of the dead letters.
+pub trait DeadLetterSink {
+    fn send(&mut self, letter: DeadLetter) -> Result<(), PipelineError>;
+
+    /// Flush the dead letters sent so far.
+    fn flush(&mut self) -> Result<(), PipelineError> {
+        Ok(())
+    }
+}
+
+impl DeadLetterSink for Vec<DeadLetter> {
+    fn send(&mut self, letter: DeadLetter) -> Result<(), PipelineError> {
+        self.push(letter);
+
+        Ok(())
+    }
+}
+
+pub type SharedDeadLetterSink = Rc<RefCell<dyn DeadLetterSink>>;
+
+/// Path in the input of the item being ingested, kept up to date by the parser.
+pub type SharedItemPath = Rc<RefCell<JsonPath>>;
+
+/// Where the items failing a stage are sent, with the path of the item being ingested.
+#[derive(Clone)]
+pub struct DeadLetters {
+    pub sink: SharedDeadLetterSink,
+    pub item_path: SharedItemPath,
+}
+
+impl DeadLetters {
+    pub fn new(sink: SharedDeadLetterSink) -> DeadLetters {
+        DeadLetters {
+            sink,
+            item_path: SharedItemPath::default(),
+        }
+    }
+}
+
+/// Wraps a stage, sending the items it fails on to the dead letters.
+pub struct DeadLetterStage {
+    stage_name: String,
+    stage: Box<dyn Pipeline>,
+    dead_letters: DeadLetters,
+}
+
+impl DeadLetterStage {
+    pub fn new(
+        stage_name: &str,
+        stage: Box<dyn Pipeline>,
+        dead_letters: DeadLetters,
+    ) -> DeadLetterStage {
+        DeadLetterStage {
+            stage_name: stage_name.to_string(),
+            stage,
+            dead_letters,
+        }
+    }
+}
+
+impl Pipeline for DeadLetterStage {
+    fn ingest(&mut self, item: JsonValue) -> Result<(), PipelineError> {
+        // Checked before the stage takes the item, so it can still be sent if it fails.
+        match self.stage.check_item(&item) {
+            Ok(()) => self.stage.ingest(item),
+            Err(error) if error.is_item_error() => {
+                self.dead_letters.sink.borrow_mut().send(DeadLetter {
+                    stage: self.stage_name.clone(),
+                    error,
+                    path: self.dead_letters.item_path.borrow().clone(),
+                    item,
+                })
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), PipelineError> {
+        self.stage.finish()?;
+        self.dead_letters.sink.borrow_mut().flush()
+    }
+
+    fn take_partial(&mut self) -> Option<PartialAggregate> {
+        self.stage.take_partial()
+    }
+
+    fn merge_partial(&mut self, partial: PartialAggregate) {
+        self.stage.merge_partial(partial)
+    }
 }
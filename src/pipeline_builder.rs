@@ -1,27 +1,122 @@
+use std::cell::{Cell, RefCell};
 use std::convert::From;
-use std::fs::OpenOptions;
+use std::fs::File;
 use std::io::stdout;
-use std::io::{stdin, Stdin};
+use std::io::{self, stdin, Read, Seek, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::path::Path;
+use std::rc::Rc;
 
 use combine::error::ParseError;
-use combine::parser::char::spaces;
-use combine::parser::item::eof;
-use combine::parser::repeat::skip_many;
 use combine::parser::Parser;
-use combine::stream::Stream;
+use memmap::{Mmap, MmapOptions};
 
-use crate::args_parser::ArgStruct;
-use crate::error::InitError;
+use crate::args_parser::{ArgStruct, ColorChoice, InputFormat, OutputFormat};
+use crate::binary_input::{CborDecoder, JsonTranscoder, MsgPackDecoder};
+use crate::error::{InitError, PipelineError};
+use crate::filter::Filter;
+use crate::json_format::FormatOptions;
 use crate::json_path::JsonPath;
 use crate::json_value::NumberVal;
-use crate::parse_query::parse_query;
-use crate::parse_smart::{json_smart, ParserState};
+use crate::ndjson::WorkerRoute;
+use crate::output::{
+    open_output_file, rotated_filename, temporary_filename, CompressedWriter, Compression,
+    CountingWriter, OutputWrite,
+};
+use crate::parse_query::{
+    build_stages, is_aggregating_stage, is_per_item_stage, parse_path, parse_query,
+    parse_query_stages,
+};
+use crate::parse_smart::{json_stream_smart, ParserOptions, ParserState, PathOutput};
 use crate::pipeline::Pipeline;
 use crate::pipeline::*;
-use crate::unicode_stream::ReadStream;
+use crate::recovery::{DeadLetterWriter, ErrorPolicy, RecordRejecter};
+use crate::unicode_stream::{ReadStream, ScanStream};
+use crate::validate::{SharedViolation, ValidatingRead, Validator};
+
+/// The text of a query, with the output of its items.
+type QueryOutput<'a> = (&'a str, Box<dyn Pipeline>);
+
+/// The filter of a query, with the pipeline of its stages.
+type QueryRoute = (Filter, Box<dyn Pipeline>);
+
+/// A query split between the workers processing the lines of a ndjson input and the main thread.
+type SplitRoute = (WorkerRoute, Box<dyn Pipeline>);
+
+const DEFAULT_MAX_OPEN_FILES: usize = 64;
 
 pub struct PipelineBuilder<'a>(&'a ArgStruct);
 
+/// Input of the parser.
+pub enum Input {
+    /// A regular file, mapped in memory to be parsed as a slice.
+    Mapped(Mmap),
+    /// Any other input, like a pipe, read through a buffer.
+    Buffered(ReadStream<Box<dyn Read + Send>>),
+}
+
+/// Maps the rest of the standard input in memory, if it's a non-empty regular file.
+#[cfg(unix)]
+fn map_stdin() -> Option<Mmap> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // The standard input must not be closed when the file is dropped.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(stdin().as_raw_fd()) });
+
+    if !file.metadata().ok()?.is_file() {
+        return None;
+    }
+
+    // The input may have been partly read before being given to us.
+    let offset = file.seek(SeekFrom::Current(0)).ok()?;
+
+    // SAFETY: the file is not expected to be modified while it's processed.
+    unsafe { MmapOptions::new().offset(offset).map(&file) }.ok()
+}
+
+#[cfg(not(unix))]
+fn map_stdin() -> Option<Mmap> {
+    None
+}
+
+/// Everything needed to build the writer stage of an output.
+#[derive(Clone)]
+struct WriterSpec {
+    format: OutputFormat,
+    indent: Option<Vec<u8>>,
+    options: FormatOptions,
+    colored: bool,
+    compression: Option<Compression>,
+}
+
+impl WriterSpec {
+    fn build<W: OutputWrite + 'static>(&self, output_writer: W) -> io::Result<Box<dyn Pipeline>> {
+        if let Some(compression) = self.compression {
+            Ok(self.build_uncompressed(CompressedWriter::new(output_writer, compression)?))
+        } else {
+            Ok(self.build_uncompressed(output_writer))
+        }
+    }
+
+    fn build_uncompressed<W: OutputWrite + 'static>(&self, output_writer: W) -> Box<dyn Pipeline> {
+        match (self.format, self.indent.clone()) {
+            (OutputFormat::Json, indent) if self.colored => {
+                Box::new(WriteColoredStage::new(output_writer, indent, self.options))
+            }
+            (OutputFormat::Json, Some(indent)) => Box::new(WritePrettyStage::with_options(
+                output_writer,
+                indent,
+                self.options,
+            )),
+            (OutputFormat::Json, None) => {
+                Box::new(WriteStage::with_options(output_writer, self.options))
+            }
+            (OutputFormat::Yaml, _) => Box::new(WriteYamlStage::new(output_writer)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum StageArg {
     Number(NumberVal),
     String(String),
@@ -29,58 +124,410 @@ pub enum StageArg {
 }
 
 impl<'a> PipelineBuilder<'a> {
-    pub fn build_input_stream(&self) -> Result<ReadStream<Stdin>, InitError> {
-        Ok(ReadStream::from_read_buffered_normalized(
-            stdin(),
-            self.0.max_text_length,
-        ))
+    /// Returns the input as JSON text.
+    pub fn build_input(&self) -> Box<dyn Read + Send> {
+        match self.0.input_format {
+            InputFormat::Json => Box::new(stdin()),
+            InputFormat::MsgPack => Box::new(JsonTranscoder::new(MsgPackDecoder::new(stdin()))),
+            InputFormat::Cbor => Box::new(JsonTranscoder::new(CborDecoder::new(stdin()))),
+        }
+    }
+
+    /// Builds the input stream, its first violation of RFC 8259 being recorded into
+    /// `violation` in strict mode.
+    pub fn build_input_stream(&self, violation: &SharedViolation) -> Result<Input, InitError> {
+        if self.0.input_format == InputFormat::Json {
+            if let Some(map) = map_stdin() {
+                if self.0.strict {
+                    // The whole input is there, so it's checked at once.
+                    *violation.lock().unwrap() = Validator::check(&map).err();
+                }
+
+                return Ok(Input::Mapped(map));
+            }
+        }
+
+        if self.0.strict {
+            let input = ValidatingRead::new(self.build_input(), violation.clone());
+
+            return Ok(Input::Buffered(ReadStream::from_read(Box::new(input))));
+        }
+
+        Ok(Input::Buffered(ReadStream::from_read(self.build_input())))
+    }
+
+    fn is_colored(&self) -> bool {
+        match self.0.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                self.0.output.is_none()
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && atty::is(atty::Stream::Stdout)
+            }
+        }
+    }
+
+    /// Returns the indentation of a depth level of the json output, or `None` if it's not prettified.
+    fn build_indent(&self) -> Option<Vec<u8>> {
+        if self.0.tab {
+            Some(b"\t".to_vec())
+        } else if let Some(width) = self.0.indent {
+            // No indentation at all is the compact output, like in jq
+            Some(vec![b' '; width]).filter(|indent| !indent.is_empty())
+        } else if self.0.pretty {
+            Some(b"  ".to_vec())
+        } else {
+            None
+        }
+    }
+
+    fn build_writer_spec(&self) -> WriterSpec {
+        WriterSpec {
+            format: self.0.output_format,
+            indent: self.build_indent(),
+            options: FormatOptions {
+                sort_keys: self.0.sort_keys,
+                ascii_output: self.0.ascii_output,
+            },
+            colored: self.is_colored(),
+            compression: self.0.compress.or_else(|| {
+                self.0
+                    .output
+                    .as_ref()
+                    .and_then(|filename| Compression::from_filename(filename))
+            }),
+        }
+    }
+
+    fn build_partitioned_output(
+        &self,
+        filename_template: &str,
+        key: &str,
+    ) -> Result<Box<dyn Pipeline>, InitError> {
+        if !filename_template.contains(PARTITION_PLACEHOLDER) {
+            return Err(InitError::MissingPartitionPlaceholder {
+                filename: filename_template.to_string(),
+            });
+        }
+
+        let key = parse_path(self.0.max_text_length, key)?;
+        let writer_spec = self.build_writer_spec();
+        let append = self.0.append;
+        let force_new = self.0.force_new;
+
+        // A partition reopened after being closed to free a file handle must not lose its content.
+        let open_output = move |filename: &str, reopen: bool| {
+            open_output_file(filename, append || reopen, force_new && !reopen)
+                .and_then(|output_writer| writer_spec.build(output_writer))
+                .map_err(|_| PipelineError::UnableToOpenOutput {
+                    filename: filename.to_string(),
+                })
+        };
+
+        Ok(Box::new(PartitionStage::new(
+            key,
+            filename_template.to_string(),
+            self.0.max_open_files.unwrap_or(DEFAULT_MAX_OPEN_FILES),
+            Box::new(open_output),
+        )))
+    }
+
+    fn build_rotated_output(&self, filename: &str) -> Result<Box<dyn Pipeline>, InitError> {
+        let writer_spec = self.build_writer_spec();
+        let append = self.0.append;
+        let force_new = self.0.force_new;
+
+        let open_output = move |filename: &str, written_bytes: Rc<Cell<u64>>| {
+            open_output_file(filename, append, force_new)
+                .and_then(|output_writer| {
+                    writer_spec.build(CountingWriter::new(output_writer, written_bytes))
+                })
+                .map_err(|_| PipelineError::UnableToOpenOutput {
+                    filename: filename.to_string(),
+                })
+        };
+
+        let stage = RotateStage::new(
+            filename.to_string(),
+            self.0.split_size,
+            self.0.split_lines,
+            Box::new(open_output),
+        )
+        .map_err(|_| InitError::UnableToOpenFile {
+            filename: rotated_filename(filename, 1),
+        })?;
+
+        Ok(Box::new(stage))
+    }
+
+    fn build_atomic_output(&self, filename: &str) -> Result<Box<dyn Pipeline>, InitError> {
+        // The temporary file will replace the target file anyway, so it has to be checked now.
+        if self.0.force_new && Path::new(filename).exists() {
+            return Err(InitError::UnableToOpenFile {
+                filename: filename.to_string(),
+            });
+        }
+
+        let temporary_filename = temporary_filename(filename);
+        let output = open_output_file(&temporary_filename, false, true)
+            .and_then(|output_writer| self.build_writer_spec().build(output_writer))
+            .map_err(|_: io::Error| InitError::UnableToOpenFile {
+                filename: temporary_filename.clone(),
+            })?;
+
+        Ok(Box::new(AtomicOutputStage::new(
+            output,
+            temporary_filename,
+            filename.to_string(),
+        )))
+    }
+
+    /// Builds the writer of an additional output file, like the one of a `tee` stage.
+    fn build_branch_output(&self, filename: &str) -> Result<Box<dyn Pipeline>, InitError> {
+        let mut writer_spec = self.build_writer_spec();
+        // The colors are only meant for the terminal of the main output.
+        writer_spec.colored = false;
+        writer_spec.compression = self
+            .0
+            .compress
+            .or_else(|| Compression::from_filename(filename));
+
+        open_output_file(filename, self.0.append, self.0.force_new)
+            .and_then(|output_writer| writer_spec.build(output_writer))
+            .map_err(|_: io::Error| InitError::UnableToOpenFile {
+                filename: filename.to_string(),
+            })
     }
 
     fn build_output(&self) -> Result<Box<dyn Pipeline>, InitError> {
         if let Some(ref filename) = self.0.output {
-            let output_writer = OpenOptions::new()
-                .write(true)
-                .truncate(!self.0.append)
-                .append(self.0.append)
-                .create(true)
-                .create_new(self.0.force_new)
-                .open(filename)
-                .map_err(|_: std::io::Error| InitError::UnableToOpenFile {
-                    filename: filename.to_string(),
-                })?;
+            if let Some(ref key) = self.0.partition_by {
+                return self.build_partitioned_output(filename, key);
+            }
+
+            if self.0.split_size.is_some() || self.0.split_lines.is_some() {
+                return self.build_rotated_output(filename);
+            }
 
-            if self.0.pretty {
-                Ok(Box::new(WritePrettyStage::new(output_writer)))
-            } else {
-                Ok(Box::new(WriteStage::new(output_writer)))
+            if self.0.atomic {
+                return self.build_atomic_output(filename);
             }
+
+            open_output_file(filename, self.0.append, self.0.force_new)
+                .and_then(|output_writer| self.build_writer_spec().build(output_writer))
+                .map_err(|_: io::Error| InitError::UnableToOpenFile {
+                    filename: filename.to_string(),
+                })
+        } else {
+            self.build_writer_spec()
+                .build(stdout())
+                .map_err(|_: io::Error| InitError::UnableToOpenFile {
+                    filename: "standard output".to_string(),
+                })
+        }
+    }
+
+    fn build_path_output(&self) -> PathOutput {
+        if self.0.paths_only {
+            PathOutput::PathOnly
+        } else if self.0.with_path {
+            PathOutput::ValueWithPath
+        } else {
+            PathOutput::Value
+        }
+    }
+
+    /// Builds the output of each query, the additional queries without an output file
+    /// of their own sharing the main output.
+    fn build_query_outputs(&self) -> Result<Vec<QueryOutput<'a>>, InitError> {
+        if self.0.query_output.len() > self.0.queries.len() {
+            return Err(InitError::TooManyQueryOutputs {
+                outputs: self.0.query_output.len(),
+                queries: self.0.queries.len(),
+            });
+        }
+
+        let queries: Vec<(&str, Option<&str>)> = self
+            .0
+            .query
+            .iter()
+            .map(|query| (query.as_str(), None))
+            .chain(self.0.queries.iter().enumerate().map(|(i, query)| {
+                (
+                    query.as_str(),
+                    self.0.query_output.get(i).map(String::as_str),
+                )
+            }))
+            .collect();
+
+        let main_output_users = queries
+            .iter()
+            .filter(|(_, filename)| filename.is_none())
+            .count();
+        let mut main_outputs = if main_output_users > 1 {
+            SharedOutputStage::split(self.build_output()?, main_output_users)
+        } else if main_output_users == 1 {
+            vec![self.build_output()?]
         } else {
-            let output_writer = stdout();
+            Vec::new()
+        };
+
+        queries
+            .into_iter()
+            .map(|(query, filename)| {
+                let output = match filename {
+                    Some(filename) => self.build_branch_output(filename)?,
+                    None => main_outputs.pop().unwrap(), // One main output was built for each of them
+                };
+
+                Ok((query, output))
+            })
+            .collect()
+    }
+
+    /// Builds the filter and the pipeline of each query.
+    fn build_routes(
+        &self,
+        dead_letters: Option<&DeadLetters>,
+    ) -> Result<Vec<QueryRoute>, InitError> {
+        self.build_query_outputs()?
+            .into_iter()
+            .map(|(query, output)| {
+                parse_query(
+                    self.0.max_text_length,
+                    output,
+                    query,
+                    &|filename| self.build_branch_output(filename),
+                    dead_letters,
+                )
+            })
+            .collect()
+    }
+
+    /// Builds the file receiving the items failing a stage, if requested.
+    pub fn build_dead_letters(&self) -> Result<Option<DeadLetters>, InitError> {
+        match self.0.dead_letter {
+            Some(ref filename) => {
+                let file = open_output_file(filename, false, false).map_err(|_| {
+                    InitError::UnableToOpenFile {
+                        filename: filename.to_string(),
+                    }
+                })?;
+                let writer: SharedDeadLetterSink =
+                    Rc::new(RefCell::new(DeadLetterWriter::new(Box::new(file))));
 
-            if self.0.pretty {
-                Ok(Box::new(WritePrettyStage::new(output_writer)))
-            } else {
-                Ok(Box::new(WriteStage::new(output_writer)))
+                Ok(Some(DeadLetters::new(writer)))
             }
+            None => Ok(None),
+        }
+    }
+
+    /// Builds each query split in two : the part run by the workers processing the lines
+    /// of a ndjson input, and the pipeline ingesting their items in the main thread.
+    ///
+    /// The workers run the stages processing each item on its own, and the aggregating stage
+    /// following them if any, whose states are merged into the one of the main thread.
+    pub fn build_split_routes(
+        &self,
+        dead_letters: Option<&DeadLetters>,
+    ) -> Result<Vec<SplitRoute>, InitError> {
+        self.build_query_outputs()?
+            .into_iter()
+            .map(|(query, output)| {
+                let (filter, stages) = parse_query_stages(self.0.max_text_length, query)?;
+                let split = stages
+                    .iter()
+                    .position(|(stage_ident, _)| !is_per_item_stage(stage_ident))
+                    .unwrap_or(stages.len());
+                // Skipping a malformed line must undo what its values added to the aggregates,
+                // so they are only run on the values of the whole lines, by the main thread.
+                let worker_end = match stages.get(split) {
+                    Some((stage_ident, _))
+                        if is_aggregating_stage(stage_ident)
+                            && self.error_policy() == ErrorPolicy::Fail =>
+                    {
+                        split + 1
+                    }
+                    _ => split,
+                };
+
+                let pipeline = build_stages(
+                    output,
+                    &stages[split..],
+                    &|filename| self.build_branch_output(filename),
+                    dead_letters,
+                )?;
+                let route = WorkerRoute::new(filter, stages[..worker_end].to_vec())?;
+
+                Ok((route, pipeline))
+            })
+            .collect()
+    }
+
+    pub fn build_parser_options(&self) -> ParserOptions {
+        ParserOptions {
+            path_output: self.build_path_output(),
+            stream: self.0.stream,
+            skip_strictness: self.0.skip_strictness,
+            arbitrary_precision: self.0.arbitrary_precision,
+            strict: self.0.strict,
         }
     }
 
-    pub fn build_parser<I>(&self) -> Result<impl Parser<Input = I, Output = ()>, InitError>
+    /// Returns the number of workers processing the lines of a ndjson input.
+    pub fn build_thread_count(&self) -> usize {
+        self.0
+            .threads
+            .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.0.on_error.unwrap_or(ErrorPolicy::Fail)
+    }
+
+    pub fn build_record_rejecter(&self) -> Result<RecordRejecter, InitError> {
+        let capture = match self.0.errors_to {
+            Some(ref filename) => Some(
+                open_output_file(filename, false, false)
+                    .map(|file| Box::new(file) as Box<dyn io::Write>)
+                    .map_err(|_| InitError::UnableToOpenFile {
+                        filename: filename.to_string(),
+                    })?,
+            ),
+            None => None,
+        };
+
+        Ok(RecordRejecter::new(self.error_policy(), capture))
+    }
+
+    pub fn max_text_length(&self) -> usize {
+        self.0.max_text_length
+    }
+
+    /// Builds the parser of the whole input, with its state to know where it failed.
+    ///
+    /// The pipelines are not finished by the parser, but by `ParserState::finish` once the
+    /// whole input is known to be valid.
+    pub fn build_parser<I>(
+        &self,
+    ) -> Result<(impl Parser<Input = I, Output = ()>, ParserState), InitError>
     where
-        I: Stream<Item = char>,
+        I: ScanStream,
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        let output = self.build_output()?;
-        let (filter, pipeline) = parse_query(self.0.max_text_length, output, &self.0.query)?;
-        let state = ParserState::new(pipeline, filter);
-        let state_finisher = state.clone();
-
-        Ok(spaces()
-            .with(skip_many(json_smart(state, self.0.max_text_length)).skip(eof()))
-            .map(move |_| {
-                state_finisher.finish().unwrap();
-                ()
-            }))
+        let dead_letters = self.build_dead_letters()?;
+        let routes = self.build_routes(dead_letters.as_ref())?;
+        let state = ParserState::new(
+            routes,
+            self.build_parser_options(),
+            dead_letters.map(|dead_letters| dead_letters.item_path),
+        );
+        let parser = json_stream_smart(state.clone(), self.0.max_text_length);
+
+        Ok((parser, state))
     }
 }
 
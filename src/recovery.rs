@@ -0,0 +1,157 @@
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::error::{InputError, PipelineError, SkippedRecords};
+use crate::json_value::JsonValue;
+use crate::pipeline::{DeadLetter, DeadLetterSink};
+
+/// What to do with a malformed record of the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop on the first one.
+    Fail,
+    /// Skip it silently, resuming at the next record.
+    Skip,
+    /// Skip it, reporting it on the standard error.
+    Warn,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorPolicy, String> {
+        match s {
+            "fail" => Ok(ErrorPolicy::Fail),
+            "skip" => Ok(ErrorPolicy::Skip),
+            "warn" => Ok(ErrorPolicy::Warn),
+            _ => Err(format!("unknown error policy : {}", s)),
+        }
+    }
+}
+
+/// A malformed record, with its raw text.
+pub struct RejectedRecord {
+    pub error: InputError,
+    pub raw: Vec<u8>,
+}
+
+/// Line of the file capturing the rejected records.
+#[derive(Serialize)]
+struct RejectedRecordLine<'a> {
+    offset: u64,
+    line: u64,
+    column: u64,
+    message: &'a str,
+    raw: &'a str,
+}
+
+/// Reports the rejected records as the error policy asks, and counts them.
+pub struct RecordRejecter {
+    policy: ErrorPolicy,
+    capture: Option<BufWriter<Box<dyn Write>>>,
+    count: u64,
+}
+
+impl RecordRejecter {
+    pub fn new(policy: ErrorPolicy, capture: Option<Box<dyn Write>>) -> RecordRejecter {
+        RecordRejecter {
+            policy,
+            capture: capture.map(BufWriter::new),
+            count: 0,
+        }
+    }
+
+    pub fn reject(&mut self, record: RejectedRecord) -> Result<(), failure::Error> {
+        if self.policy == ErrorPolicy::Fail {
+            return Err(record.error.into());
+        }
+
+        self.count += 1;
+
+        if self.policy == ErrorPolicy::Warn {
+            eprintln!("Warning: skipping a record, {}", record.error);
+        }
+
+        if let Some(ref mut capture) = self.capture {
+            let raw = String::from_utf8_lossy(&record.raw);
+            let line = RejectedRecordLine {
+                offset: record.error.offset,
+                line: record.error.line,
+                column: record.error.column,
+                message: &record.error.message,
+                raw: raw.trim_end_matches(['\n', '\r']),
+            };
+
+            serde_json::to_writer(&mut *capture, &line)
+                .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+            capture
+                .write_all(b"\n")
+                .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the captured records, failing if any record was skipped.
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        if let Some(ref mut capture) = self.capture {
+            capture
+                .flush()
+                .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        }
+
+        if self.count > 0 {
+            return Err(SkippedRecords { count: self.count }.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Line of the file of the dead letters.
+#[derive(Serialize)]
+struct DeadLetterLine<'a> {
+    stage: &'a str,
+    error: String,
+    /// Path in the input of the value failing the stage.
+    path: String,
+    item: &'a JsonValue,
+}
+
+/// Writes the dead letters as json lines.
+pub struct DeadLetterWriter(BufWriter<Box<dyn Write>>);
+
+impl DeadLetterWriter {
+    pub fn new(writer: Box<dyn Write>) -> DeadLetterWriter {
+        DeadLetterWriter(BufWriter::new(writer))
+    }
+}
+
+impl DeadLetterSink for DeadLetterWriter {
+    fn send(&mut self, letter: DeadLetter) -> Result<(), PipelineError> {
+        let line = DeadLetterLine {
+            stage: &letter.stage,
+            error: letter.error.to_string(),
+            path: match letter.error.path() {
+                Some(path) => letter.path.join(path),
+                None => letter.path,
+            }
+            .to_string(),
+            item: &letter.item,
+        };
+
+        serde_json::to_writer(&mut self.0, &line)
+            .map_err(|_| PipelineError::UnableToWriteOuptut)?;
+        self.0
+            .write_all(b"\n")
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+
+    fn flush(&mut self) -> Result<(), PipelineError> {
+        self.0
+            .flush()
+            .map_err(|_| PipelineError::UnableToWriteOuptut)
+    }
+}
@@ -1,49 +1,454 @@
 use std::io::Read;
-use combine_elastic_buffered_stream::ElasticBufferedReadStream;
 
-use combine::stream::{Positioned, Resetable, StreamErrorFor, StreamOnce};
+use combine::easy::{self, Errors};
+use combine::error::StreamError;
+use combine::stream::{Positioned, Resetable, Stream, StreamErrorFor, StreamOnce};
 
-pub struct ReadStream<R: Read>(
-    ElasticBufferedReadStream<R>,
-);
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of bytes kept buffered before the cursor, to reset the stream to a checkpoint.
+/// The parsers of the input only go back over a token they failed to recognize,
+/// so the checkpoints before are not kept alive to be reset to.
+const MAX_LOOKBACK: u64 = 4 * CHUNK_SIZE as u64;
+
+/// Max number of bytes of the input shown on each side of a located byte.
+const EXCERPT_CONTEXT: usize = 32;
+
+/// Stream exposing its buffered input, so it can be consumed in bulk instead of byte by byte.
+pub trait ScanStream: Stream<Item = u8, Range = u8, Position = u64> {
+    /// Feed the next bytes of the input to `scan`, until it returns the number of them it consumed.
+    /// The bytes fed before are consumed entirely.
+    ///
+    /// Returns `false` if the input ended before `scan` was done.
+    fn scan_bytes(
+        &mut self,
+        scan: &mut dyn FnMut(&[u8]) -> Option<usize>,
+    ) -> Result<bool, StreamErrorFor<Self>>;
+
+    /// Locate a position of the input, which has to be after the checkpoints the stream
+    /// can be reset to, or the cursor, to be exact.
+    fn locate(&self, position: u64) -> Location;
+
+    /// Error which stopped the reading of the input before its end, if any.
+    fn read_error(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Position of a byte of the input, with an excerpt of its line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub offset: u64,
+    /// Starting at 1.
+    pub line: u64,
+    /// Counted in bytes, starting at 1.
+    pub column: u64,
+    pub excerpt: String,
+}
+
+impl Location {
+    /// Locate the byte at `pos` in `bytes`, a part of the input starting at `offset`,
+    /// on the line `line` which starts at `line_start`.
+    pub fn in_bytes(bytes: &[u8], pos: usize, offset: u64, line: u64, line_start: u64) -> Location {
+        let pos = pos.min(bytes.len());
+        let last_newline = bytes[..pos].iter().rposition(|&b| b == b'\n');
+        let (line, line_start) = match last_newline {
+            Some(last_newline) => (
+                line + bytecount(&bytes[..pos], b'\n'),
+                offset + last_newline as u64 + 1,
+            ),
+            None => (line, line_start),
+        };
+
+        let excerpt_start = std::cmp::max(
+            last_newline.map_or(0, |last_newline| last_newline + 1),
+            pos.saturating_sub(EXCERPT_CONTEXT),
+        );
+        let excerpt_end = bytes[pos..]
+            .iter()
+            .take(EXCERPT_CONTEXT)
+            .position(|&b| b == b'\n' || b == b'\r')
+            .map_or_else(
+                || std::cmp::min(bytes.len(), pos + EXCERPT_CONTEXT),
+                |len| pos + len,
+            );
+
+        Location {
+            offset: offset + pos as u64,
+            line,
+            column: offset + pos as u64 - line_start + 1,
+            excerpt: String::from_utf8_lossy(&bytes[excerpt_start..excerpt_end]).into_owned(),
+        }
+    }
+}
+
+fn bytecount(bytes: &[u8], needle: u8) -> u64 {
+    bytes.iter().filter(|&&b| b == needle).count() as u64
+}
+
+pub struct ReadStream<R: Read> {
+    raw_read: R,
+    buffer: Vec<u8>,
+    // Position in the stream of the first byte of the buffer
+    buffer_offset: u64,
+    // Line of the first byte of the buffer, and the position of its start
+    buffer_line: u64,
+    buffer_line_start: u64,
+    cursor_pos: usize,
+    eof: bool,
+    // Error of the last read, returned again by the next ones so it isn't taken for the end
+    read_error: Option<(std::io::ErrorKind, String)>,
+}
 
 impl<R: Read> ReadStream<R> {
     pub fn from_read(input: R) -> ReadStream<R> {
-        ReadStream(
-            ElasticBufferedReadStream::new(input),
-        )
+        ReadStream {
+            raw_read: input,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+            buffer_line: 1,
+            buffer_line_start: 0,
+            cursor_pos: 0,
+            eof: false,
+            read_error: None,
+        }
+    }
+
+    /// Drop the buffered bytes too far behind the cursor to reset the stream to them.
+    fn free_useless_bytes(&mut self) {
+        let min_pos = self
+            .position()
+            .saturating_sub(MAX_LOOKBACK)
+            .max(self.buffer_offset);
+        let useless_len = (min_pos - self.buffer_offset) as usize;
+
+        let useless_bytes = &self.buffer[..useless_len];
+        if let Some(last_newline) = useless_bytes.iter().rposition(|&b| b == b'\n') {
+            self.buffer_line += bytecount(useless_bytes, b'\n');
+            self.buffer_line_start = self.buffer_offset + last_newline as u64 + 1;
+        }
+
+        self.buffer.drain(..useless_len);
+        self.buffer_offset = min_pos;
+        self.cursor_pos -= useless_len;
+    }
+
+    /// Read the next chunk of the input into the buffer.
+    ///
+    /// Returns `false` if the input is ended.
+    fn fill_buffer(&mut self) -> std::io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+
+        if let Some((kind, ref message)) = self.read_error {
+            return Err(std::io::Error::new(kind, message.clone()));
+        }
+
+        self.free_useless_bytes();
+
+        let len = self.buffer.len();
+        self.buffer.resize(len + CHUNK_SIZE, 0);
+
+        let result = loop {
+            match self.raw_read.read(&mut self.buffer[len..]) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                result => break result,
+            }
+        };
+        let read_len = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(len + read_len);
+
+        match result {
+            Ok(read_len) => {
+                self.eof = read_len == 0;
+
+                Ok(read_len > 0)
+            }
+            Err(e) => {
+                self.read_error = Some((e.kind(), e.to_string()));
+
+                Err(e)
+            }
+        }
     }
 }
 
 impl<R: Read> StreamOnce for ReadStream<R> {
-    type Item = <ElasticBufferedReadStream<R> as StreamOnce>::Item;
-    type Range = <ElasticBufferedReadStream<R> as StreamOnce>::Range;
-    type Position = <ElasticBufferedReadStream<R> as StreamOnce>::Position;
-    type Error = <ElasticBufferedReadStream<R> as StreamOnce>::Error;
+    type Item = u8;
+    type Range = u8;
+    type Position = u64;
+    type Error = Errors<u8, u8, u64>;
 
-    fn uncons(&mut self) -> Result<Self::Item, StreamErrorFor<Self>> {
-        self.0.uncons()
-    }
+    fn uncons(&mut self) -> Result<u8, StreamErrorFor<Self>> {
+        if self.cursor_pos == self.buffer.len() && !self.fill_buffer()? {
+            return Err(StreamErrorFor::<Self>::end_of_input());
+        }
 
-    fn is_partial(&self) -> bool {
-        self.0.is_partial()
+        let item = self.buffer[self.cursor_pos];
+        self.cursor_pos += 1;
+
+        Ok(item)
     }
 }
 
 impl<R: Read> Resetable for ReadStream<R> {
-    type Checkpoint = <ElasticBufferedReadStream<R> as Resetable>::Checkpoint;
+    type Checkpoint = u64;
 
-    fn checkpoint(&self) -> Self::Checkpoint {
-        self.0.checkpoint()
+    fn checkpoint(&self) -> u64 {
+        self.position()
     }
 
-    fn reset(&mut self, checkpoint: Self::Checkpoint) {
-        self.0.reset(checkpoint);
+    fn reset(&mut self, checkpoint: u64) {
+        match checkpoint.checked_sub(self.buffer_offset) {
+            Some(pos) => self.cursor_pos = pos as usize,
+            None => {
+                // The input from the checkpoint is not buffered anymore, so the parsing
+                // is stopped by an error instead of going on from another position.
+                self.read_error = Some((
+                    std::io::ErrorKind::Other,
+                    "unable to go back that far in the input".to_string(),
+                ));
+                self.eof = false;
+                self.cursor_pos = self.buffer.len();
+            }
+        }
     }
 }
 
 impl<R: Read> Positioned for ReadStream<R> {
     fn position(&self) -> Self::Position {
-        self.0.position()
+        self.buffer_offset + self.cursor_pos as u64
+    }
+}
+
+impl<R: Read> ScanStream for ReadStream<R> {
+    fn scan_bytes(
+        &mut self,
+        scan: &mut dyn FnMut(&[u8]) -> Option<usize>,
+    ) -> Result<bool, StreamErrorFor<Self>> {
+        loop {
+            if self.cursor_pos == self.buffer.len() && !self.fill_buffer()? {
+                return Ok(false);
+            }
+
+            match scan(&self.buffer[self.cursor_pos..]) {
+                Some(consumed_len) => {
+                    self.cursor_pos += consumed_len;
+
+                    return Ok(true);
+                }
+                None => self.cursor_pos = self.buffer.len(),
+            }
+        }
+    }
+
+    fn locate(&self, position: u64) -> Location {
+        // The bytes before the buffer are not known anymore.
+        let pos = position.saturating_sub(self.buffer_offset) as usize;
+
+        Location::in_bytes(
+            &self.buffer,
+            pos,
+            self.buffer_offset,
+            self.buffer_line,
+            self.buffer_line_start,
+        )
+    }
+
+    fn read_error(&self) -> Option<String> {
+        self.read_error.as_ref().map(|(_, message)| message.clone())
+    }
+}
+
+/// Stream over an input entirely in memory, like a memory-mapped file.
+pub struct SliceStream<'a> {
+    bytes: &'a [u8],
+    cursor_pos: usize,
+}
+
+impl<'a> SliceStream<'a> {
+    pub fn new(bytes: &'a [u8]) -> SliceStream<'a> {
+        SliceStream {
+            bytes,
+            cursor_pos: 0,
+        }
+    }
+}
+
+impl<'a> StreamOnce for SliceStream<'a> {
+    type Item = u8;
+    type Range = u8;
+    type Position = u64;
+    type Error = Errors<u8, u8, u64>;
+
+    fn uncons(&mut self) -> Result<u8, StreamErrorFor<Self>> {
+        let item = *self
+            .bytes
+            .get(self.cursor_pos)
+            .ok_or_else(StreamErrorFor::<Self>::end_of_input)?;
+        self.cursor_pos += 1;
+
+        Ok(item)
+    }
+}
+
+impl<'a> Resetable for SliceStream<'a> {
+    type Checkpoint = usize;
+
+    fn checkpoint(&self) -> usize {
+        self.cursor_pos
+    }
+
+    fn reset(&mut self, checkpoint: usize) {
+        self.cursor_pos = checkpoint;
+    }
+}
+
+impl<'a> Positioned for SliceStream<'a> {
+    fn position(&self) -> Self::Position {
+        self.cursor_pos as u64
+    }
+}
+
+impl<'a> ScanStream for SliceStream<'a> {
+    fn scan_bytes(
+        &mut self,
+        scan: &mut dyn FnMut(&[u8]) -> Option<usize>,
+    ) -> Result<bool, StreamErrorFor<Self>> {
+        if self.cursor_pos == self.bytes.len() {
+            return Ok(false);
+        }
+
+        match scan(&self.bytes[self.cursor_pos..]) {
+            Some(consumed_len) => {
+                self.cursor_pos += consumed_len;
+
+                Ok(true)
+            }
+            None => {
+                self.cursor_pos = self.bytes.len();
+
+                Ok(false)
+            }
+        }
+    }
+
+    fn locate(&self, position: u64) -> Location {
+        Location::in_bytes(self.bytes, position as usize, 0, 1, 0)
+    }
+}
+
+// The parser works on the stream wrapped by `easy_parse`.
+impl<S: ScanStream> ScanStream for easy::Stream<S> {
+    fn scan_bytes(
+        &mut self,
+        scan: &mut dyn FnMut(&[u8]) -> Option<usize>,
+    ) -> Result<bool, StreamErrorFor<Self>> {
+        self.0.scan_bytes(scan).map_err(StreamError::into_other)
+    }
+
+    fn locate(&self, position: u64) -> Location {
+        self.0.locate(position)
+    }
+
+    fn read_error(&self) -> Option<String> {
+        self.0.read_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_to_checkpoint_after_refill() {
+        let input: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let mut stream = ReadStream::from_read(&input[..]);
+
+        for _ in 0..CHUNK_SIZE - 1 {
+            stream.uncons().unwrap();
+        }
+
+        let checkpoint = stream.checkpoint();
+        for byte in &input[CHUNK_SIZE - 1..2 * CHUNK_SIZE + 10] {
+            assert_eq!(stream.uncons().unwrap(), *byte);
+        }
+
+        stream.reset(checkpoint);
+        assert_eq!(stream.position(), CHUNK_SIZE as u64 - 1);
+        assert_eq!(stream.uncons().unwrap(), input[CHUNK_SIZE - 1]);
+    }
+
+    #[test]
+    fn fail_on_reset_too_far_behind() {
+        let input = vec![b' '; 2 * MAX_LOOKBACK as usize];
+        let mut stream = ReadStream::from_read(&input[..]);
+
+        let checkpoint = stream.checkpoint();
+        for _ in 0..MAX_LOOKBACK + CHUNK_SIZE as u64 + 1 {
+            stream.uncons().unwrap();
+        }
+        assert!(stream.buffer.len() <= MAX_LOOKBACK as usize + CHUNK_SIZE);
+
+        stream.reset(checkpoint);
+        assert!(stream.uncons().is_err());
+        assert!(stream.read_error().is_some());
+    }
+
+    #[test]
+    fn scan_across_chunks() {
+        let mut input = vec![b' '; 2 * CHUNK_SIZE];
+        input.push(b'x');
+        input.push(b'y');
+        let mut stream = ReadStream::from_read(&input[..]);
+
+        let done = stream
+            .scan_bytes(&mut |bytes| bytes.iter().position(|&b| b == b'x').map(|pos| pos + 1))
+            .unwrap();
+
+        assert!(done);
+        assert_eq!(stream.position(), 2 * CHUNK_SIZE as u64 + 1);
+        assert_eq!(stream.uncons().unwrap(), b'y');
+        assert!(stream.uncons().is_err());
+    }
+
+    #[test]
+    fn locate_after_refill() {
+        let mut input = b"[1,\n".repeat(CHUNK_SIZE / 2);
+        input.extend_from_slice(b"2, x3]");
+        let mut stream = ReadStream::from_read(&input[..]);
+
+        while stream.uncons().unwrap() != b'x' {}
+
+        let position = stream.position() - 1;
+        assert_eq!(
+            stream.locate(position),
+            Location {
+                offset: 2 * CHUNK_SIZE as u64 + 3,
+                line: CHUNK_SIZE as u64 / 2 + 1,
+                column: 4,
+                excerpt: "2, x3]".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn scan_slice_like_read() {
+        let input = b"[1, \"a\"] x";
+        let mut stream = SliceStream::new(input);
+
+        assert_eq!(stream.uncons().unwrap(), b'[');
+        let checkpoint = stream.checkpoint();
+        let done = stream
+            .scan_bytes(&mut |bytes| bytes.iter().position(|&b| b == b']').map(|pos| pos + 1))
+            .unwrap();
+
+        assert!(done);
+        assert_eq!(stream.position(), 8);
+        stream.reset(checkpoint);
+        assert_eq!(stream.uncons().unwrap(), b'1');
+        assert!(!stream.scan_bytes(&mut |_| None).unwrap());
+        assert!(stream.uncons().is_err());
     }
 }
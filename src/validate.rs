@@ -0,0 +1,527 @@
+//! Validation of the input against RFC 8259, which the parsers are more lenient than.
+//!
+//! The input is a stream of JSON texts, separated by whitespaces.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{describe_bytes, NonConformingInput};
+use crate::unicode_stream::Location;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// First byte of the input not conforming to RFC 8259.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub offset: u64,
+    pub message: String,
+}
+
+/// Violation found by a `ValidatingRead`, shared with the parser of its input.
+pub type SharedViolation = Arc<Mutex<Option<Violation>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Container {
+    Array,
+    Object,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberPart {
+    Minus,
+    Zero,
+    Integer,
+    Point,
+    Fraction,
+    Exponent,
+    ExponentSign,
+    ExponentDigits,
+}
+
+impl NumberPart {
+    /// Whether the number can end after this part.
+    fn is_complete(self) -> bool {
+        match self {
+            NumberPart::Zero
+            | NumberPart::Integer
+            | NumberPart::Fraction
+            | NumberPart::ExponentDigits => true,
+            NumberPart::Minus
+            | NumberPart::Point
+            | NumberPart::Exponent
+            | NumberPart::ExponentSign => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StringPart {
+    Unescaped,
+    Escape,
+    /// Number of hexadecimal digits of a unicode escape sequence still expected.
+    Unicode(u8),
+    /// Number of continuation bytes of a UTF-8 sequence still expected, and the range
+    /// of the next one, narrower for the second byte of some sequences.
+    Utf8 {
+        remaining: u8,
+        low: u8,
+        high: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Before a top level value.
+    Start,
+    /// After a top level value, which has to be followed by a whitespace.
+    End,
+    /// A value expected, or the end of the array if it's its first element.
+    Value {
+        first: bool,
+    },
+    /// A key expected, or the end of the object if it's its first field.
+    Key {
+        first: bool,
+    },
+    Colon,
+    /// After an element of an array or a field of an object.
+    Next,
+    String {
+        key: bool,
+        part: StringPart,
+    },
+    Number(NumberPart),
+    Keyword(&'static [u8]),
+}
+
+fn is_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\n' || b == b'\r'
+}
+
+fn is_value_start(b: u8) -> bool {
+    matches!(
+        b,
+        b'{' | b'[' | b'"' | b'-' | b'0'..=b'9' | b't' | b'f' | b'n'
+    )
+}
+
+fn unexpected(b: u8, expected: &str) -> String {
+    format!("unexpected {}, expected {}", describe_bytes(&[b]), expected)
+}
+
+/// Incremental validator of a stream of JSON texts.
+pub struct Validator {
+    state: State,
+    containers: Vec<Container>,
+    /// Offset in the input of the next byte.
+    offset: u64,
+    has_value: bool,
+}
+
+impl Default for Validator {
+    fn default() -> Validator {
+        Validator::new()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator {
+            state: State::Start,
+            containers: Vec::new(),
+            offset: 0,
+            has_value: false,
+        }
+    }
+
+    /// Checks a whole input.
+    pub fn check(bytes: &[u8]) -> Result<(), Violation> {
+        let mut validator = Validator::new();
+
+        validator.feed(bytes)?;
+        validator.finish()
+    }
+
+    /// Checks the next bytes of the input.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), Violation> {
+        for &b in bytes {
+            self.state = self.next_state(b).map_err(|message| Violation {
+                offset: self.offset,
+                message,
+            })?;
+            self.offset += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the input ended after a whole value.
+    pub fn finish(&mut self) -> Result<(), Violation> {
+        let message = match self.state {
+            State::Start if !self.has_value => "no value in the input",
+            State::Start | State::End => return Ok(()),
+            State::Number(part) if part.is_complete() && self.containers.is_empty() => {
+                return Ok(())
+            }
+            _ => "unexpected end of input",
+        };
+
+        Err(Violation {
+            offset: self.offset,
+            message: message.to_string(),
+        })
+    }
+
+    /// State after the end of a value.
+    fn after_value(&mut self) -> State {
+        if self.containers.is_empty() {
+            self.has_value = true;
+            State::End
+        } else {
+            State::Next
+        }
+    }
+
+    fn start_value(&mut self, b: u8) -> Result<State, String> {
+        Ok(match b {
+            b'{' => {
+                self.containers.push(Container::Object);
+                State::Key { first: true }
+            }
+            b'[' => {
+                self.containers.push(Container::Array);
+                State::Value { first: true }
+            }
+            b'"' => State::String {
+                key: false,
+                part: StringPart::Unescaped,
+            },
+            b'-' => State::Number(NumberPart::Minus),
+            b'0' => State::Number(NumberPart::Zero),
+            b'1'..=b'9' => State::Number(NumberPart::Integer),
+            b't' => State::Keyword(b"rue"),
+            b'f' => State::Keyword(b"alse"),
+            b'n' => State::Keyword(b"ull"),
+            b'+' => return Err("sign `+` before a number".to_string()),
+            _ => return Err(unexpected(b, "a value")),
+        })
+    }
+
+    fn close_container(&mut self, b: u8) -> Result<State, String> {
+        let expected = match self.containers.last() {
+            Some(Container::Array) => b']',
+            Some(Container::Object) => b'}',
+            None => return Err(unexpected(b, "a value")),
+        };
+
+        if b != expected {
+            return Err(unexpected(b, &describe_bytes(&[expected])));
+        }
+
+        self.containers.pop();
+
+        Ok(self.after_value())
+    }
+
+    fn next_state(&mut self, b: u8) -> Result<State, String> {
+        match self.state {
+            State::Start | State::Value { .. } | State::Key { .. } | State::Colon | State::Next
+                if is_whitespace(b) =>
+            {
+                Ok(self.state)
+            }
+            State::Start => self.start_value(b),
+            State::End if is_whitespace(b) => Ok(State::Start),
+            State::End if is_value_start(b) => {
+                Err("missing whitespace between two values".to_string())
+            }
+            State::End => Err(unexpected(b, "a whitespace")),
+            State::Value { first: true } if b == b']' => self.close_container(b),
+            State::Value { .. } => self.start_value(b),
+            State::Key { .. } if b == b'"' => Ok(State::String {
+                key: true,
+                part: StringPart::Unescaped,
+            }),
+            State::Key { first: true } if b == b'}' => self.close_container(b),
+            State::Key { .. } => Err(unexpected(b, "a string key")),
+            State::Colon if b == b':' => Ok(State::Value { first: false }),
+            State::Colon => Err(unexpected(b, "`:`")),
+            State::Next if b == b',' => match self.containers.last() {
+                Some(Container::Object) => Ok(State::Key { first: false }),
+                _ => Ok(State::Value { first: false }),
+            },
+            State::Next if b == b']' || b == b'}' => self.close_container(b),
+            State::Next => match self.containers.last() {
+                Some(Container::Object) => Err(unexpected(b, "`,` or `}`")),
+                _ => Err(unexpected(b, "`,` or `]`")),
+            },
+            State::String { key, part } => self.next_string_state(key, part, b),
+            State::Number(part) => self.next_number_state(part, b),
+            State::Keyword(rest) if b == rest[0] && rest.len() == 1 => Ok(self.after_value()),
+            State::Keyword(rest) if b == rest[0] => Ok(State::Keyword(&rest[1..])),
+            State::Keyword(_) => Err(unexpected(b, "`true`, `false` or `null`")),
+        }
+    }
+
+    fn next_string_state(&mut self, key: bool, part: StringPart, b: u8) -> Result<State, String> {
+        let part = match part {
+            StringPart::Unescaped => match b {
+                b'"' if key => return Ok(State::Colon),
+                b'"' => return Ok(self.after_value()),
+                b'\\' => StringPart::Escape,
+                0x00..=0x1f => return Err("unescaped control character in string".to_string()),
+                0x20..=0x7f => StringPart::Unescaped,
+                0xc2..=0xdf => StringPart::Utf8 {
+                    remaining: 1,
+                    low: 0x80,
+                    high: 0xbf,
+                },
+                // Without the overlong encodings, nor the UTF-16 surrogates.
+                0xe0 => StringPart::Utf8 {
+                    remaining: 2,
+                    low: 0xa0,
+                    high: 0xbf,
+                },
+                0xed => StringPart::Utf8 {
+                    remaining: 2,
+                    low: 0x80,
+                    high: 0x9f,
+                },
+                0xe1..=0xef => StringPart::Utf8 {
+                    remaining: 2,
+                    low: 0x80,
+                    high: 0xbf,
+                },
+                // Without the overlong encodings, nor the code points above U+10FFFF.
+                0xf0 => StringPart::Utf8 {
+                    remaining: 3,
+                    low: 0x90,
+                    high: 0xbf,
+                },
+                0xf1..=0xf3 => StringPart::Utf8 {
+                    remaining: 3,
+                    low: 0x80,
+                    high: 0xbf,
+                },
+                0xf4 => StringPart::Utf8 {
+                    remaining: 3,
+                    low: 0x80,
+                    high: 0x8f,
+                },
+                _ => return Err("invalid UTF-8 in string".to_string()),
+            },
+            StringPart::Escape => match b {
+                b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => StringPart::Unescaped,
+                b'u' => StringPart::Unicode(4),
+                _ => return Err("invalid escape sequence".to_string()),
+            },
+            StringPart::Unicode(remaining) if b.is_ascii_hexdigit() => {
+                if remaining == 1 {
+                    StringPart::Unescaped
+                } else {
+                    StringPart::Unicode(remaining - 1)
+                }
+            }
+            StringPart::Unicode(_) => return Err("invalid unicode escape sequence".to_string()),
+            StringPart::Utf8 {
+                remaining,
+                low,
+                high,
+            } if (low..=high).contains(&b) => {
+                if remaining == 1 {
+                    StringPart::Unescaped
+                } else {
+                    StringPart::Utf8 {
+                        remaining: remaining - 1,
+                        low: 0x80,
+                        high: 0xbf,
+                    }
+                }
+            }
+            StringPart::Utf8 { .. } => return Err("invalid UTF-8 in string".to_string()),
+        };
+
+        Ok(State::String { key, part })
+    }
+
+    fn next_number_state(&mut self, part: NumberPart, b: u8) -> Result<State, String> {
+        let next = match (part, b) {
+            (NumberPart::Minus, b'0') => NumberPart::Zero,
+            (NumberPart::Minus, b'1'..=b'9') => NumberPart::Integer,
+            (NumberPart::Minus, _) => return Err(unexpected(b, "a digit after `-`")),
+            (NumberPart::Zero, b'0'..=b'9') => return Err("leading zero in number".to_string()),
+            (NumberPart::Integer, b'0'..=b'9') => NumberPart::Integer,
+            (NumberPart::Zero, b'.') | (NumberPart::Integer, b'.') => NumberPart::Point,
+            (NumberPart::Point, b'0'..=b'9') | (NumberPart::Fraction, b'0'..=b'9') => {
+                NumberPart::Fraction
+            }
+            (NumberPart::Point, _) => return Err(unexpected(b, "a digit after `.`")),
+            (NumberPart::Zero, b'e')
+            | (NumberPart::Zero, b'E')
+            | (NumberPart::Integer, b'e')
+            | (NumberPart::Integer, b'E')
+            | (NumberPart::Fraction, b'e')
+            | (NumberPart::Fraction, b'E') => NumberPart::Exponent,
+            (NumberPart::Exponent, b'+') | (NumberPart::Exponent, b'-') => NumberPart::ExponentSign,
+            (NumberPart::Exponent, b'0'..=b'9')
+            | (NumberPart::ExponentSign, b'0'..=b'9')
+            | (NumberPart::ExponentDigits, b'0'..=b'9') => NumberPart::ExponentDigits,
+            (NumberPart::Exponent, _) | (NumberPart::ExponentSign, _) => {
+                return Err(unexpected(b, "a digit in the exponent"))
+            }
+            // The number is complete, the byte is the start of what follows it.
+            _ => {
+                self.state = self.after_value();
+                return self.next_state(b);
+            }
+        };
+
+        Ok(State::Number(next))
+    }
+}
+
+/// Reader stopping at the first violation of RFC 8259 of its input, which is shared so the
+/// parser of what was read before can report it.
+pub struct ValidatingRead<R: Read> {
+    input: R,
+    validator: Validator,
+    violation: SharedViolation,
+}
+
+impl<R: Read> ValidatingRead<R> {
+    pub fn new(input: R, violation: SharedViolation) -> ValidatingRead<R> {
+        ValidatingRead {
+            input,
+            validator: Validator::new(),
+            violation,
+        }
+    }
+}
+
+impl<R: Read> Read for ValidatingRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut violation = self.violation.lock().unwrap();
+
+        if violation.is_none() {
+            let offset = self.validator.offset;
+            let len = self.input.read(buf)?;
+            let checked = if len == 0 {
+                self.validator.finish()
+            } else {
+                self.validator.feed(&buf[..len])
+            };
+
+            match checked {
+                Ok(()) => return Ok(len),
+                Err(found) => {
+                    let valid_len = (found.offset - offset) as usize;
+                    *violation = Some(found);
+
+                    // The bytes before the violation are still parsed.
+                    if valid_len > 0 {
+                        return Ok(valid_len);
+                    }
+                }
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "non-conforming JSON",
+        ))
+    }
+}
+
+/// Checks the whole input, reporting its first violation of RFC 8259.
+pub fn validate_input<R: Read>(mut input: R) -> Result<(), failure::Error> {
+    let mut validator = Validator::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let (mut line, mut line_start) = (1, 0);
+
+    loop {
+        let offset = validator.offset;
+        let len = match input.read(&mut chunk) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        };
+        let bytes = &chunk[..len];
+
+        let checked = if len == 0 {
+            validator.finish()
+        } else {
+            validator.feed(bytes)
+        };
+
+        if let Err(violation) = checked {
+            let pos = (violation.offset - offset) as usize;
+            let location = Location::in_bytes(bytes, pos, offset, line, line_start);
+
+            return Err(NonConformingInput::new(violation, location).into());
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        if let Some(last_newline) = bytes.iter().rposition(|&b| b == b'\n') {
+            line += bytes.iter().filter(|&&b| b == b'\n').count() as u64;
+            line_start = offset + last_newline as u64 + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn check_conformance_suite() {
+        let suite = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/json_conformance");
+        let mut checked = 0;
+
+        for entry in fs::read_dir(suite).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let result = Validator::check(&fs::read(&path).unwrap());
+
+            if name.starts_with("y_") {
+                assert_eq!(result, Ok(()), "{} should be accepted", name);
+            } else if name.starts_with("n_") {
+                assert!(result.is_err(), "{} should be rejected", name);
+            } else {
+                continue;
+            }
+
+            checked += 1;
+        }
+
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn locate_the_first_violation() {
+        let violation = Validator::check(b"{\"a\": [1, 2]}\n{\"b\": 01}").unwrap_err();
+
+        assert_eq!(violation.offset, 21);
+        assert_eq!(violation.message, "leading zero in number");
+
+        let violation = Validator::check(b"[1][2]").unwrap_err();
+
+        assert_eq!(violation.offset, 3);
+        assert_eq!(violation.message, "missing whitespace between two values");
+        assert_eq!(Validator::check(b" [1] [2]\n"), Ok(()));
+    }
+
+    #[test]
+    fn stop_reading_at_the_first_violation() {
+        let violation = SharedViolation::default();
+        let mut read = ValidatingRead::new(&b"[1, 2] [+3]"[..], violation.clone());
+        let mut buf = Vec::new();
+
+        assert!(read.read_to_end(&mut buf).is_err());
+        assert_eq!(buf, b"[1, 2] [");
+        assert_eq!(violation.lock().unwrap().as_ref().unwrap().offset, 8);
+    }
+}
@@ -194,3 +194,1060 @@ fn it_refuses_to_write_to_existing_file_when_requested() {
 
     temp_dir.close().unwrap();
 }
+
+#[test]
+fn it_reads_msgpack_input_when_requested() {
+    // {"a": {"b": 1}} {"a": {"b": -2.5}}
+    let input: &[u8] = &[
+        0x81, 0xa1, b'a', 0x81, 0xa1, b'b', 0x01, 0x81, 0xa1, b'a', 0x81, 0xa1, b'b', 0xcb, 0xc0,
+        0x04, 0, 0, 0, 0, 0, 0,
+    ];
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--input-format", "msgpack", ".a.b"])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout("1\n-2.5\n");
+}
+
+#[test]
+fn it_reads_cbor_input_when_requested() {
+    // {"a": [1, 2]} {"a": [_ 3]}
+    let input: &[u8] = &[
+        0xa1, 0x61, b'a', 0x82, 0x01, 0x02, 0xa1, 0x61, b'a', 0x9f, 0x03, 0xff,
+    ];
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--input-format", "cbor", ". | sum .a[1]"])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    // -2^64, below the range of 64 bits integers
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--input-format", "cbor", "--arbitrary-precision", "."])
+        .with_stdin()
+        .buffer(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..])
+        .assert()
+        .success()
+        .stdout("-18446744073709551616\n");
+}
+
+#[test]
+fn it_reports_malformed_binary_input() {
+    // 1, then the reserved marker 0xc1
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--input-format", "msgpack", "."])
+        .with_stdin()
+        .buffer(&[0x01, 0xc1][..])
+        .assert()
+        .failure()
+        .stdout("1\n")
+        .stderr(predicate::str::contains("invalid MessagePack marker 0xc1"));
+
+    // Map of 2^64 - 1 entries
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--input-format", "cbor", "."])
+        .with_stdin()
+        .buffer(&[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("too many entries in map"));
+}
+
+#[test]
+fn it_outputs_yaml_when_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--output-format", "yaml", "."])
+        .with_stdin()
+        .buffer("{\"zone\": \"1\", \"id\": 1, \"tags\": [\"a\", null]}{\"id\": 2.5}")
+        .assert()
+        .success()
+        .stdout("---\nzone: \"1\"\nid: 1\ntags:\n  - a\n  - ~\n---\nid: 2.5\n");
+}
+
+#[test]
+fn it_colorizes_output_when_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--color", "always", "."])
+        .with_stdin()
+        .buffer("{\"a\": [1, \"b\", false, null]}")
+        .assert()
+        .success()
+        .stdout("{\x1b[1;34m\"a\"\x1b[0m:[\x1b[36m1\x1b[0m,\x1b[32m\"b\"\x1b[0m,\x1b[33mfalse\x1b[0m,\x1b[1;30mnull\x1b[0m]}\n");
+}
+
+#[test]
+fn it_does_not_colorize_piped_output_by_default() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["."])
+        .with_stdin()
+        .buffer("{\"a\": true}")
+        .assert()
+        .success()
+        .stdout("{\"a\":true}\n");
+}
+
+#[test]
+fn it_formats_output_as_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--tab", "--sort-keys", "--ascii-output", "."])
+        .with_stdin()
+        .buffer("{\"b\": \"é\", \"a\": {\"d\": 1, \"c\": 2}}")
+        .assert()
+        .success()
+        .stdout("{\n\t\"a\": {\n\t\t\"c\": 2,\n\t\t\"d\": 1\n\t},\n\t\"b\": \"\\u00e9\"\n}\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--indent", "4", "."])
+        .with_stdin()
+        .buffer("{\"a\": [1]}")
+        .assert()
+        .success()
+        .stdout("{\n    \"a\": [\n        1\n    ]\n}\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--indent", "0", "."])
+        .with_stdin()
+        .buffer("{\"a\": [1]}")
+        .assert()
+        .success()
+        .stdout("{\"a\":[1]}\n");
+}
+
+#[test]
+fn it_partitions_output_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let filename_template = temp_dir.child("customer-{}.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            filename_template.path().to_str().unwrap(),
+            "--partition-by",
+            ".customer",
+            "--max-open-files",
+            "1",
+            ".",
+        ])
+        .with_stdin()
+        .buffer("{\"customer\": \"a\", \"n\": 1}{\"customer\": 2, \"n\": 2}{\"customer\": \"a\", \"n\": 3}{\"n\": 4}")
+        .assert()
+        .success();
+
+    temp_dir
+        .child("customer-a.json")
+        .assert("{\"customer\":\"a\",\"n\":1}\n{\"customer\":\"a\",\"n\":3}\n");
+    temp_dir
+        .child("customer-2.json")
+        .assert("{\"customer\":2,\"n\":2}\n");
+    temp_dir.child("customer-null.json").assert("{\"n\":4}\n");
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_keeps_partitions_inside_the_output_directory() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let output_dir = temp_dir.child("out");
+    output_dir.create_dir_all().unwrap();
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_dir.child("{}").path().to_str().unwrap(),
+            "--partition-by",
+            ".k",
+            ".",
+        ])
+        .with_stdin()
+        .buffer("{\"k\": \"..\"}{\"k\": \".\"}{\"k\": \"\"}{\"k\": \"a/..\"}")
+        .assert()
+        .success();
+
+    output_dir.child("__").assert("{\"k\":\"..\"}\n");
+    output_dir
+        .child("_")
+        .assert("{\"k\":\".\"}\n{\"k\":\"\"}\n");
+    output_dir.child("a_..").assert("{\"k\":\"a/..\"}\n");
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_checks_the_max_number_of_open_partitions() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--max-open-files", "2", "."])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--partition-by"));
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            "out-{}.json",
+            "--partition-by",
+            ".k",
+            "--max-open-files",
+            "0",
+            ".",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid count : 0"));
+}
+
+#[test]
+fn it_rotates_output_files_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let output_file = temp_dir.child("out.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--split-lines",
+            "2",
+            ".a",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": 1}{\"a\": 2}{\"a\": 3}")
+        .assert()
+        .success();
+
+    temp_dir.child("out.0001.json").assert("1\n2\n");
+    temp_dir.child("out.0002.json").assert("3\n");
+    temp_dir
+        .child("out.0003.json")
+        .assert(predicate::path::missing());
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--split-size",
+            "4",
+            ".a",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": \"x\"}{\"a\": \"y\"}")
+        .assert()
+        .success();
+
+    temp_dir.child("out.0001.json").assert("\"x\"\n");
+    temp_dir.child("out.0002.json").assert("\"y\"\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--split-lines",
+            "0",
+            ".a",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid count : 0"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_writes_output_atomically_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let output_file = temp_dir.child("temp-output.json");
+
+    output_file.write_str("Previous content.").unwrap();
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--atomic",
+            ".",
+        ])
+        .with_stdin()
+        .buffer("{\"test\": true}{\"test\": ")
+        .assert()
+        .failure();
+
+    output_file.assert("Previous content.");
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--atomic",
+            ".",
+        ])
+        .with_stdin()
+        .buffer("{\"test\": true}")
+        .assert()
+        .success();
+
+    output_file.assert("{\"test\":true}\n");
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_compresses_output_when_requested() {
+    use std::io::Read;
+
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let output_file = temp_dir.child("temp-output.json.gz");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--output", output_file.path().to_str().unwrap(), "."])
+        .with_stdin()
+        .buffer("{\"test\": true}")
+        .assert()
+        .success();
+
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(std::fs::File::open(output_file.path()).unwrap())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, "{\"test\":true}\n");
+
+    let output = Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--compress", "zstd", "."])
+        .with_stdin()
+        .buffer("{\"test\": true}")
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        zstd::decode_all(&output.stdout[..]).unwrap(),
+        b"{\"test\":true}\n".to_vec()
+    );
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_tees_items_into_a_file_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let tee_file = temp_dir.child("orders.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[&format!(
+            ". | tee \"{}\" | sum .total",
+            tee_file.path().to_str().unwrap()
+        )])
+        .with_stdin()
+        .buffer("{\"total\": 1}{\"total\": 2}")
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    tee_file.assert("{\"total\":1}\n{\"total\":2}\n");
+
+    let output_file = temp_dir.child("colored.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--output",
+            output_file.path().to_str().unwrap(),
+            "--append",
+            "--color",
+            "always",
+            &format!(". | tee \"{}\"", tee_file.path().to_str().unwrap()),
+        ])
+        .with_stdin()
+        .buffer("{\"total\": 3}")
+        .assert()
+        .success();
+
+    output_file.assert("{\x1b[1;34m\"total\"\x1b[0m:\x1b[36m3\x1b[0m}\n");
+    tee_file.assert("{\"total\":1}\n{\"total\":2}\n{\"total\":3}\n");
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_runs_several_queries_over_one_parse_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let names_file = temp_dir.child("names.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "-e",
+            "[0,1].name",
+            "--query-output",
+            names_file.path().to_str().unwrap(),
+            "-e",
+            "[0,1] | sum .total",
+        ])
+        .with_stdin()
+        .buffer("[{\"name\": \"a\", \"total\": 1}, {\"name\": \"b\", \"total\": 2}]")
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    names_file.assert("\"a\"\n\"b\"\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "-e",
+            ".name",
+            "--query-output",
+            names_file.path().to_str().unwrap(),
+            "--query-output",
+            "totals.json",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "2 query outputs given for 1 additional queries",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_outputs_paths_when_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--with-path", ".a[0,1]"])
+        .with_stdin()
+        .buffer("{\"a\": [1, 2]}")
+        .assert()
+        .success()
+        .stdout(
+            "{\"path\":\".\\\"a\\\"[0]\",\"value\":1}\n{\"path\":\".\\\"a\\\"[1]\",\"value\":2}\n",
+        );
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--paths-only", ".a[0,1]"])
+        .with_stdin()
+        .buffer("{\"a\": [1, 2]}")
+        .assert()
+        .success()
+        .stdout("\".\\\"a\\\"[0]\"\n\".\\\"a\\\"[1]\"\n");
+}
+
+#[test]
+fn it_streams_path_events_when_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--stream", ".a"])
+        .with_stdin()
+        .buffer("{\"a\": {\"b\": [1, {}], \"c\": []}}")
+        .assert()
+        .success()
+        .stdout("[[\"b\",0],1]\n[[\"b\",1],{}]\n[[\"b\",1]]\n[[\"c\"],[]]\n[[\"c\"]]\n");
+
+    // The paths of each query are relative to its own kept value
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let b_file = temp_dir.child("b.json");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--stream",
+            ".a",
+            "-e",
+            ".a.b",
+            "--query-output",
+            b_file.path().to_str().unwrap(),
+        ])
+        .with_stdin()
+        .buffer("{\"a\": {\"b\": [1, {}], \"c\": []}}")
+        .assert()
+        .success()
+        .stdout("[[\"b\",0],1]\n[[\"b\",1],{}]\n[[\"b\",1]]\n[[\"c\"],[]]\n[[\"c\"]]\n");
+
+    b_file.assert("[[0],1]\n[[1],{}]\n[[1]]\n");
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_copies_kept_values_minified_without_stages() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .with_stdin()
+        .buffer("{\"a\": [ 1 , \"x\\ty\" , { \"b\" : null } ]}\n{\"a\": true}")
+        .assert()
+        .success()
+        .stdout("[1,\"x\\ty\",{\"b\":null}]\ntrue\n");
+}
+
+#[test]
+fn it_skips_discarded_values_structurally_when_requested() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--skip-strictness", "structural", ".b"])
+        .with_stdin()
+        .buffer("{\"a\": [1, {\"x\": \"]}\\\"\"}, [[]]], \"b\": 2}")
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--skip-strictness", "structural", ".b"])
+        .with_stdin()
+        .buffer("{\"a\": [1, {\"x\": 2]], \"b\": 2}")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn it_processes_ndjson_lines_in_parallel_in_order() {
+    let input: String = (0..2000)
+        .map(|i| format!("{{\"id\": {}, \"total\": {}}}\n", i, i % 7))
+        .collect();
+
+    let expected_output: String = (0..2000).map(|i| format!("{}\n", i)).collect();
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--ndjson", "--threads", "4", ".id"])
+        .with_stdin()
+        .buffer(input.as_str())
+        .assert()
+        .success()
+        .stdout(expected_output);
+
+    let expected_sum: i64 = (0..2000).map(|i| i % 7).sum();
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--ndjson", "--threads", "4", ". | sum .total"])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", expected_sum));
+}
+
+#[test]
+fn it_parses_input_files_mapped_in_memory() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let input_file = temp_dir.child("input.json");
+    input_file
+        .write_str("{\"a\": \"caf\\u00e9\", \"b\": 1}\n{\"a\": [1, \"x\"]}")
+        .unwrap();
+
+    // A regular file as standard input is mapped instead of being read
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .stdin(std::fs::File::open(input_file.path()).unwrap())
+        .assert()
+        .success()
+        .stdout("\"café\"\n[1,\"x\"]\n");
+}
+
+#[test]
+fn it_locates_input_errors() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .with_stdin()
+        .buffer("{\"a\": 1}\n{\"b\": [1, 2,\n  {\"c\": tru}]}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "invalid input at line 3, column 9 (byte 30), in .\"b\" : unexpected `}`, expected true\n      {\"c\": tru}]}",
+        ));
+}
+
+#[test]
+fn it_points_at_query_syntax_errors() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a..b"])
+        .with_stdin()
+        .buffer("{}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "syntax error in the query at position 4 : unexpected `.`, expected `\"`, letter or `/`\n    .a..b\n       ^",
+        ));
+}
+
+#[test]
+fn it_suggests_close_stage_names() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a | ad_field .b 1"])
+        .with_stdin()
+        .buffer("{}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown stage : ad_field, did you mean add_field ?",
+        ));
+}
+
+#[test]
+fn it_skips_malformed_ndjson_lines_when_requested() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let errors_file = temp_dir.child("rejected.jsonl");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--ndjson",
+            "--on-error",
+            "skip",
+            "--errors-to",
+            errors_file.path().to_str().unwrap(),
+            ". | sum .a",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": 1}\n{\"a\": 2, \"b\": tru}\n{\"a\": 3}\n")
+        .assert()
+        .code(2)
+        .stdout("4\n")
+        .stderr(predicate::str::contains("1 malformed records skipped"));
+
+    errors_file.assert(
+        "{\"offset\":23,\"line\":2,\"column\":15,\"message\":\"unexpected `}`, expected true\",\"raw\":\"{\\\"a\\\": 2, \\\"b\\\": tru}\"}\n",
+    );
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_warns_about_skipped_ndjson_lines() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--ndjson", "--on-error", "warn", ".a"])
+        .with_stdin()
+        .buffer("{\"a\": 1}\n{\"a\": [2\n{\"a\": 3}\n")
+        .assert()
+        .code(2)
+        .stdout("1\n3\n")
+        .stderr(predicate::str::contains(
+            "Warning: skipping a record, invalid input at line 2",
+        ));
+}
+
+#[test]
+fn it_sets_aside_items_failing_a_stage() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let dead_letter_file = temp_dir.child("dead-letters.jsonl");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--dead-letter",
+            dead_letter_file.path().to_str().unwrap(),
+            ". | sum .a",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": 1} {\"a\": \"x\"} {\"a\": 4}")
+        .assert()
+        .success()
+        .stdout("5\n");
+
+    dead_letter_file.assert(
+        "{\"stage\":\"sum\",\"error\":\"number expected at .\\\"a\\\" but got \\\"x\\\"\",\"path\":\".\\\"a\\\"\",\"item\":{\"a\":\"x\"}}\n",
+    );
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_sets_aside_items_with_their_path_in_the_input() {
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let dead_letter_file = temp_dir.child("dead-letters.jsonl");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--dead-letter",
+            dead_letter_file.path().to_str().unwrap(),
+            ".a | add_field \"k\" \"v\"",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": 3} {\"a\": {}}")
+        .assert()
+        .success()
+        .stdout("{\"k\":\"v\"}\n");
+
+    dead_letter_file.assert(
+        "{\"stage\":\"add_field\",\"error\":\"object expected but got 3\",\"path\":\".\\\"a\\\"\",\"item\":3}\n",
+    );
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--ndjson",
+            "--dead-letter",
+            dead_letter_file.path().to_str().unwrap(),
+            ".a | sum .b",
+        ])
+        .with_stdin()
+        .buffer("{\"a\": {\"b\": 1}}\n{\"a\": {\"b\": \"x\"}}\n")
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    dead_letter_file.assert(
+        "{\"stage\":\"sum\",\"error\":\"number expected at .\\\"b\\\" but got \\\"x\\\"\",\"path\":\".\\\"a\\\".\\\"b\\\"\",\"item\":{\"b\":\"x\"}}\n",
+    );
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn it_reports_stage_errors_without_dead_letters() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[". | add_field \"k\" \"v\""])
+        .with_stdin()
+        .buffer("{} 3")
+        .assert()
+        .failure()
+        .stdout("{\"k\":\"v\"}\n")
+        .stderr(predicate::str::starts_with(
+            "Error: object expected but got 3",
+        ));
+}
+
+#[test]
+fn it_reports_invalid_regexes_and_numbers() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["./a(/"])
+        .with_stdin()
+        .buffer("{}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "syntax error in the query at position 2 : invalid regex, unclosed group",
+        ));
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["."])
+        .with_stdin()
+        .buffer("[1, 99999999999999999999]")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("number out of range"));
+}
+
+#[test]
+fn it_rejects_too_deeply_nested_input() {
+    let nested = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+
+    for query in &[".", ".b"] {
+        Command::cargo_bin(crate_name!())
+            .unwrap()
+            .args(&[query])
+            .with_stdin()
+            .buffer(nested.as_str())
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "invalid input at line 1, column 129 (byte 128)",
+            ))
+            .stderr(predicate::str::contains("too deeply nested"));
+    }
+}
+
+#[test]
+fn it_keeps_unsigned_64_bits_integers() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".id"])
+        .with_stdin()
+        .buffer(r#"{"id": 18446744073709551615} {"id": -9223372036854775808}"#)
+        .assert()
+        .success()
+        .stdout("18446744073709551615\n-9223372036854775808\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[". | sum .id"])
+        .with_stdin()
+        .buffer(r#"{"id": 18446744073709551615} {"id": 18446744073709551615}"#)
+        .assert()
+        .success()
+        .stdout("36893488147419103230\n");
+}
+
+#[test]
+fn it_keeps_the_digits_of_numbers_in_arbitrary_precision() {
+    let input = "[0.10000000000000000001,123456789012345678901234567890,1e400]";
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--arbitrary-precision", "."])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", input));
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--arbitrary-precision", ". | sum .a"])
+        .with_stdin()
+        .buffer(r#"{"a": 123456789012345678901234567890} {"a": 1}"#)
+        .assert()
+        .success()
+        .stdout("123456789012345678901234567891\n");
+}
+
+#[test]
+fn it_writes_the_same_digits_in_yaml_as_in_json() {
+    let input = r#"{"a":[1.10,-0.0,1E+2,0.10000000000000000001,123456789012345678901234567890]}"#;
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--arbitrary-precision", "."])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", input));
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--arbitrary-precision", "--output-format", "yaml", "."])
+        .with_stdin()
+        .buffer(input)
+        .assert()
+        .success()
+        .stdout(
+            "---\na:\n  - 1.10\n  - -0.0\n  - 1E+2\n  - 0.10000000000000000001\n  - 123456789012345678901234567890\n",
+        );
+}
+
+#[test]
+fn it_reports_integer_overflows_in_sums() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--arbitrary-precision", ". | sum .a"])
+        .with_stdin()
+        .buffer(r#"{"a": 170141183460469231731687303715884105727} {"a": 1}"#)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            r#"integer overflow in the sum of ."a""#,
+        ));
+}
+
+#[test]
+fn it_writes_kept_numbers_back_unchanged() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["."])
+        .with_stdin()
+        .buffer(r#"{"price": 1.10, "quantity": 1e3, "rate": -2.50E-2}"#)
+        .assert()
+        .success()
+        .stdout("{\"price\":1.10,\"quantity\":1e3,\"rate\":-2.50E-2}\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[". | sum .price"])
+        .with_stdin()
+        .buffer(r#"{"price": 1.10} {"price": 1e3}"#)
+        .assert()
+        .success()
+        .stdout("1001.1\n");
+}
+
+#[test]
+fn it_rejects_non_conforming_input_in_strict_mode() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .with_stdin()
+        .buffer(r#"{"a": [1, 01]}"#)
+        .assert()
+        .success()
+        .stdout("[1,1]\n");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--strict", ".a"])
+        .with_stdin()
+        .buffer(r#"{"a": [1, 01]}"#)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            r#"line 1, column 12 (byte 11), in ."a" : leading zero in number"#,
+        ));
+
+    // Mapped in memory
+    let temp_dir = assert_fs::TempDir::new().unwrap();
+    let input_file = temp_dir.child("input.json");
+    input_file.write_str(r#"{"a": [1, 01]}"#).unwrap();
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--strict", ".a"])
+        .stdin(std::fs::File::open(input_file.path()).unwrap())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            r#"line 1, column 12 (byte 11), in ."a" : leading zero in number"#,
+        ));
+
+    // The values before the violation are valid on their own
+    for &(input, message) in &[
+        (
+            "1 01",
+            "line 1, column 4 (byte 3), in . : leading zero in number",
+        ),
+        (
+            "{\"a\":1} +1",
+            "line 1, column 9 (byte 8), in . : sign `+` before a number",
+        ),
+        (
+            "  ",
+            "line 1, column 3 (byte 2), in . : no value in the input",
+        ),
+    ] {
+        Command::cargo_bin(crate_name!())
+            .unwrap()
+            .args(&["--strict", "."])
+            .with_stdin()
+            .buffer(input)
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(message));
+
+        input_file.write_str(input).unwrap();
+        Command::cargo_bin(crate_name!())
+            .unwrap()
+            .args(&["--strict", "."])
+            .stdin(std::fs::File::open(input_file.path()).unwrap())
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(message));
+    }
+
+    // Nor are the outputs of the values before the violation
+    let output_file = temp_dir.child("output.json");
+    output_file.write_str("Previous content.").unwrap();
+    input_file.write_str("[1] 01").unwrap();
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[
+            "--strict",
+            "--atomic",
+            "--output",
+            output_file.path().to_str().unwrap(),
+            ".",
+        ])
+        .stdin(std::fs::File::open(input_file.path()).unwrap())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "line 1, column 6 (byte 5), in . : leading zero in number",
+        ));
+
+    output_file.assert("Previous content.");
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 2);
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--strict", "--ndjson", "--on-error", "skip", ".a"])
+        .with_stdin()
+        .buffer("{\"a\": 1}\n{\"a\": \"\\x\"}\n{\"a\": 3}\n")
+        .assert()
+        .code(2)
+        .stdout("1\n3\n");
+}
+
+#[test]
+fn it_validates_the_input() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--validate"])
+        .with_stdin()
+        .buffer("{\"a\": [1, 2]}\n{\"b\": \"\\u00e9\"}\n")
+        .assert()
+        .success()
+        .stdout("");
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--validate"])
+        .with_stdin()
+        .buffer("{\"a\": [1, 2]}\n{\"b\": +1}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "non-conforming JSON at line 2, column 7 (byte 20) : sign `+` before a number",
+        ));
+
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&["--validate"])
+        .with_stdin()
+        .buffer("  ")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "non-conforming JSON at line 1, column 3 (byte 2) : no value in the input",
+        ));
+}
+
+#[test]
+fn it_copies_duplicated_fields_like_kept_values() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .with_stdin()
+        .buffer("{\"a\": {\"x\": 1, \"y\": [{\"k\": 2, \"k\": 3}], \"x\": {}}}")
+        .assert()
+        .success()
+        .stdout("{\"x\":{},\"y\":[{\"k\":3}]}\n");
+}
+
+#[test]
+fn it_copies_nothing_of_an_invalid_value() {
+    Command::cargo_bin(crate_name!())
+        .unwrap()
+        .args(&[".a"])
+        .with_stdin()
+        .buffer("{\"a\": [1, 2]}\n{\"a\": [3, {\"b\": x}]}")
+        .assert()
+        .failure()
+        .stdout("[1,2]\n");
+}